@@ -62,7 +62,7 @@
           pkgs.cargo-vet
           pkgs.cargo-outdated
           pkgs.cargo-audit
-          ancient-poetry.defaultPackage.x86_64-linux
+          ancient-poetry.defaultPackage.${system}
           bacon
         ];
       };
@@ -1,11 +1,11 @@
 use crate::vcs::{ParsedVCS, TofuVCS};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
 #[allow(unused_imports)]
 use log::debug;
 use serde::de::Deserializer;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 use std::prelude::v1::Result as StdResult;
 
@@ -99,6 +99,10 @@ pub struct ConfigToml {
     pub dev_shell: Option<DevShell>,
     #[serde(rename = "R")]
     pub r: Option<R>,
+    pub julia: Option<Julia>,
+    pub node: Option<Node>,
+    #[serde(default)]
+    pub packages: BTreeMap<String, LocalPackage>,
 }
 
 #[derive(Debug)]
@@ -121,6 +125,9 @@ pub struct TofuConfigToml {
     pub flakes: HashMap<String, TofuFlake>,
     pub dev_shell: TofuDevShell,
     pub r: Option<TofuR>,
+    pub julia: Option<TofuJulia>,
+    pub node: Option<Node>,
+    pub packages: BTreeMap<String, LocalPackage>,
 }
 
 //todo: refactor
@@ -293,6 +300,10 @@ pub struct NixPkgs {
 
     pub permitted_insecure_packages: Option<Vec<String>>,
     pub overlay: Option<String>,
+    /// passed on as `import nixpkgs { config = ...; }`
+    pub config: Option<toml::Table>,
+    /// overlay files (relative to anysnake2.toml), copied into the flake
+    pub overlays: Option<Vec<String>>,
 }
 
 impl NixPkgs {
@@ -303,6 +314,8 @@ impl NixPkgs {
             allow_unfree: Self::default_allow_unfree(),
             permitted_insecure_packages: None,
             overlay: None,
+            config: None,
+            overlays: None,
         }
     }
     pub fn default_allow_unfree() -> bool {
@@ -317,6 +330,8 @@ pub struct TofuNixPkgs {
     pub allow_unfree: bool,
     pub permitted_insecure_packages: Option<Vec<String>>,
     pub overlay: Option<String>,
+    pub config: toml::Table,
+    pub overlays: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -330,14 +345,158 @@ pub struct Cmd {
 
 #[derive(Deserialize, Debug)]
 pub struct Rust {
+    /// a version for stable, a date (yyyy-mm-dd) for beta and nightly,
+    /// or 'file' to read ./rust-toolchain.toml
     pub version: Option<String>,
     pub url: Option<ParsedVCS>,
+    pub toolchain_file: Option<String>,
+    pub channel: Option<RustChannel>,
+    pub profile: Option<RustProfile>,
+    #[serde(default)]
+    pub components: Vec<String>,
+    #[serde(default)]
+    pub targets: Vec<String>,
+    pub tools: Option<HashMap<String, RustTool>>,
+}
+
+/// A rust program built with `rustPlatform.buildRustPackage` and added to the container.
+/// Either `name = "version"` (crates.io), `name = {version = "..."}` or `name = {url = "github:..."}`
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "RustToolToml")]
+pub struct RustTool {
+    pub source: RustToolSource,
+    pub cargo_hash: Option<String>,
+    /// the crate version / rev the hashes were discovered for
+    pub hashes_for: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum RustToolSource {
+    /// version may be empty -> newest
+    Crate {
+        version: String,
+        hash: Option<String>,
+    },
+    Vcs(ParsedVCS),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RustToolToml {
+    Version(String),
+    Table {
+        version: Option<String>,
+        url: Option<ParsedVCS>,
+        hash: Option<String>,
+        cargo_hash: Option<String>,
+        hashes_for: Option<String>,
+    },
+}
+
+impl TryFrom<RustToolToml> for RustTool {
+    type Error = String;
+
+    fn try_from(value: RustToolToml) -> StdResult<Self, Self::Error> {
+        Ok(match value {
+            RustToolToml::Version(version) => RustTool {
+                source: RustToolSource::Crate {
+                    version,
+                    hash: None,
+                },
+                cargo_hash: None,
+                hashes_for: None,
+            },
+            RustToolToml::Table {
+                version,
+                url,
+                hash,
+                cargo_hash,
+                hashes_for,
+            } => match (version, url) {
+                (version, None) => RustTool {
+                    source: RustToolSource::Crate {
+                        version: version.unwrap_or_default(),
+                        hash,
+                    },
+                    cargo_hash,
+                    hashes_for,
+                },
+                (None, Some(url)) => {
+                    if hash.is_some() {
+                        return Err("rust tools from urls take no hash, they are pinned by the rev in the url".to_string());
+                    }
+                    RustTool {
+                        source: RustToolSource::Vcs(url),
+                        cargo_hash,
+                        hashes_for,
+                    }
+                }
+                (Some(_), Some(_)) => {
+                    return Err("rust tools take either a (crates.io) version or an url".to_string())
+                }
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct TofuRustTool {
+    pub source: TofuRustToolSource,
+    pub cargo_hash: String,
+}
+
+#[derive(Debug)]
+pub enum TofuRustToolSource {
+    Crate { version: String, hash: String },
+    Vcs(TofuVCS),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RustChannel {
+    Stable,
+    Beta,
+    Nightly,
+}
+
+impl RustChannel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RustChannel::Stable => "stable",
+            RustChannel::Beta => "beta",
+            RustChannel::Nightly => "nightly",
+        }
+    }
+}
+
+/// rustup profiles, as offered by rust-overlay
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RustProfile {
+    Minimal,
+    Default,
+}
+
+impl RustProfile {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RustProfile::Minimal => "minimal",
+            RustProfile::Default => "default",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct TofuRust {
     pub version: String,
     pub url: TofuVCS,
+    /// rustup toolchain file - takes precedence over version/channel/profile
+    pub toolchain_file: Option<String>,
+    pub channel: RustChannel,
+    pub profile: RustProfile,
+    pub components: Vec<String>,
+    pub targets: Vec<String>,
+    pub tools: BTreeMap<String, TofuRustTool>,
 }
 
 /* #[derive(Deserialize, Debug)]
@@ -370,6 +529,11 @@ impl PythonPackageSource {
                 | url.starts_with("git+https")
                 | url.starts_with("git+ssh")
                 | url.starts_with("hg+https:/")
+                | url.starts_with("tarball+")
+                | url.starts_with("gitlab:")
+                | url.starts_with("sourcehut:")
+                | url.starts_with("forgejo:")
+                | url.starts_with("codeberg:")
             {
                 let vcs = ParsedVCS::try_from(url)?;
                 PythonPackageSource::Vcs(vcs)
@@ -408,12 +572,20 @@ impl TofuPythonPackageSource {
                     branch: branch.clone(),
                     rev: rev.clone(),
                 }),
-                TofuVCS::Mercurial { url, rev } => {
+                TofuVCS::Mercurial { url, branch, rev } => {
                     TofuPythonPackageSource::Vcs(TofuVCS::Mercurial {
                         url: remove_username_from_url(url),
+                        branch: branch.clone(),
                         rev: rev.clone(),
                     })
                 }
+                TofuVCS::Path { .. } | TofuVCS::Forge { .. } => {
+                    TofuPythonPackageSource::Vcs(vcs.clone())
+                }
+                TofuVCS::Tarball { url, hash } => TofuPythonPackageSource::Vcs(TofuVCS::Tarball {
+                    url: remove_username_from_url(url),
+                    hash: hash.clone(),
+                }),
             },
             TofuPythonPackageSource::Url(url) => {
                 TofuPythonPackageSource::Url(remove_username_from_url(url))
@@ -431,6 +603,30 @@ impl TofuPythonPackageSource {
 #[cfg(test)]
 mod test {}
 
+/// uv2nix 'sourcePreference' - build from wheels or sdists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourcePreference {
+    Wheel,
+    Sdist,
+}
+
+impl SourcePreference {
+    pub fn from_prefer_wheels(prefer_wheels: bool) -> Self {
+        if prefer_wheels {
+            SourcePreference::Wheel
+        } else {
+            SourcePreference::Sdist
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SourcePreference::Wheel => "wheel",
+            SourcePreference::Sdist => "sdist",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PythonPackageDefinition {
     pub source: PythonPackageSource,
@@ -439,6 +635,11 @@ pub struct PythonPackageDefinition {
     pub anysnake_override_attrs: Option<HashMap<String, String>>,
     pub patch_before_lock: Option<String>,
     pub build_systems: Option<Vec<String>>,
+    pub ecosystem_date: Option<String>,
+    pub prefer: Option<SourcePreference>,
+    pub patches: Vec<String>,
+    /// vcs urls only: follow the newest matching tag instead of the newest commit
+    pub tag_regex: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -449,6 +650,9 @@ pub struct TofuPythonPackageDefinition {
     pub anysnake_override_attrs: Option<HashMap<String, String>>,
     pub patch_before_lock: Option<String>,
     pub build_systems: Option<Vec<String>>,
+    pub ecosystem_date: Option<String>,
+    pub prefer: Option<SourcePreference>,
+    pub patches: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -526,6 +730,10 @@ impl<'de> Deserialize<'de> for PythonPackageDefinition {
                     anysnake_override_attrs: None,
                     patch_before_lock: None,
                     build_systems: None,
+                    ecosystem_date: None,
+                    prefer: None,
+                    patches: Vec::new(),
+                    tag_regex: None,
                 })
             }
             StrOrHashMap::HashMap(parsed) => {
@@ -546,6 +754,10 @@ impl<'de> Deserialize<'de> for PythonPackageDefinition {
                     "editable",
                     "patch_before_lock",
                     "build_systems",
+                    "ecosystem_date",
+                    "prefer",
+                    "patches",
+                    "tag_regex",
                 ];
                 for key in &parsed {
                     if !allowed_keys.contains(&key.0.as_str()) {
@@ -650,6 +862,67 @@ impl<'de> Deserialize<'de> for PythonPackageDefinition {
                     ),
                     None => None,
                 };
+                let ecosystem_date = match parsed.get("ecosystem_date") {
+                    Some(entry) => {
+                        let date = entry
+                            .as_str()
+                            .context("ecosystem_date was not a string")
+                            .map_err(serde::de::Error::custom)?;
+                        parse_my_date(date).map_err(|e| {
+                            serde::de::Error::custom(format!(
+                                "ecosystem_date {date:?} is not a valid yyyy-mm-dd date: {e}"
+                            ))
+                        })?;
+                        if !matches!(source, PythonPackageSource::VersionConstraint(_)) {
+                            return Err(serde::de::Error::custom(
+                                "ecosystem_date is only supported for packages resolved from PyPI by version constraint (not pypi:, url or vcs)",
+                            ));
+                        }
+                        Some(date.to_string())
+                    }
+                    None => None,
+                };
+                let prefer = match parsed.get("prefer").map(toml::Value::as_str) {
+                    Some(Some("wheel")) => Some(SourcePreference::Wheel),
+                    Some(Some("sdist")) => Some(SourcePreference::Sdist),
+                    Some(other) => {
+                        return Err(serde::de::Error::custom(format!(
+                            "prefer must be \"wheel\" or \"sdist\", but was {other:?}",
+                        )));
+                    }
+                    None => None,
+                };
+                let patches = match parsed.get("patches") {
+                    Some(entry) => entry
+                        .as_array()
+                        .context("patches was not an array")
+                        .map_err(serde::de::Error::custom)?
+                        .iter()
+                        .map(|x| {
+                            x.as_str()
+                                .context("patches entry was not a string (path to a patch file)")
+                                .map_err(serde::de::Error::custom)
+                                .map(std::string::ToString::to_string)
+                        })
+                        .collect::<Result<Vec<String>, _>>()?,
+                    None => Vec::new(),
+                };
+                let tag_regex = match parsed.get("tag_regex") {
+                    Some(toml::Value::String(tag_regex)) => {
+                        if !matches!(source, PythonPackageSource::Vcs(_)) {
+                            return Err(serde::de::Error::custom(
+                                "tag_regex is only supported for vcs urls (github:, git+https:, ...)",
+                            ));
+                        }
+                        Some(tag_regex.clone())
+                    }
+                    Some(other) => {
+                        return Err(serde::de::Error::custom(format!(
+                            "tag_regex must be a string, but was {other:?}",
+                        )));
+                    }
+                    None => None,
+                };
                 Ok(PythonPackageDefinition {
                     source,
                     editable_path: editable,
@@ -657,6 +930,10 @@ impl<'de> Deserialize<'de> for PythonPackageDefinition {
                     anysnake_override_attrs: None,
                     patch_before_lock,
                     build_systems,
+                    ecosystem_date,
+                    prefer,
+                    patches,
+                    tag_regex,
                 })
             }
         }
@@ -709,6 +986,20 @@ pub struct Python {
     pub ecosystem_date: Option<String>,
     pub packages: HashMap<SafePythonName, PythonPackageDefinition>,
     pub uv_lock_env: Option<HashMap<String, String>>,
+    pub envs: Option<HashMap<String, PythonEnv>>,
+}
+
+/// An additional python environment, exposed at /envs/<name> inside the container
+#[derive(Deserialize, Debug, Clone)]
+pub struct PythonEnv {
+    pub version: String,
+    pub ecosystem_date: Option<String>,
+    #[serde(default)]
+    pub packages: HashMap<SafePythonName, PythonPackageDefinition>,
+    pub uv_lock_env: Option<HashMap<String, String>>,
+    /// link this environment as /bin/python instead of [python]
+    #[serde(default)]
+    pub default: bool,
 }
 
 #[derive(Debug)]
@@ -717,6 +1008,22 @@ pub struct TofuPython {
     pub ecosystem_date: String,
     pub packages: HashMap<SafePythonName, TofuPythonPackageDefinition>,
     pub uv_lock_env: Option<HashMap<String, String>>,
+    pub envs: BTreeMap<String, TofuPythonEnv>,
+}
+
+#[derive(Debug)]
+pub struct TofuPythonEnv {
+    pub version: String,
+    pub ecosystem_date: String,
+    pub packages: HashMap<SafePythonName, TofuPythonPackageDefinition>,
+    pub uv_lock_env: Option<HashMap<String, String>>,
+    pub default: bool,
+}
+
+impl TofuPythonEnv {
+    pub fn parsed_ecosystem_date(&self) -> Result<jiff::civil::Date> {
+        parse_my_date(&self.ecosystem_date)
+    }
 }
 
 impl TofuPython {
@@ -732,14 +1039,26 @@ impl TofuPython {
         }
         false
     }
+
+    /// the [python.envs.<name>] that's /bin/python, if it's not [python] itself
+    pub fn default_env(&self) -> Option<&str> {
+        self.envs
+            .iter()
+            .find(|(_, env)| env.default)
+            .map(|(name, _)| name.as_str())
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Flake {
-    pub url: ParsedVCS,
+    pub url: Option<ParsedVCS>,
+    /// a local flake, relative to anysnake2.toml. Alternative to url
+    pub path: Option<String>,
     pub dir: Option<String>,
     pub follows: Option<Vec<String>>,
     pub packages: Option<Vec<String>>,
+    /// follow the newest tag matching this instead of the newest commit
+    pub tag_regex: Option<String>,
 }
 
 #[derive(Debug)]
@@ -770,6 +1089,33 @@ pub struct R {
     //rebuild R with the same nixpkgs that your python is from
     //preventing glibc issues when using rpy2
     pub use_inside_nix_pkgs: Option<bool>,
+    pub vcs_packages: Option<HashMap<String, RVcsPackage>>,
+}
+
+/// An R package built from a github/git/mercurial source
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RVcsPackage {
+    pub url: ParsedVCS,
+    #[serde(default, deserialize_with = "deserialize_editable")]
+    pub editable: Option<String>,
+}
+
+/// editable = true -> 'code', editable = "somewhere" -> 'somewhere'
+fn deserialize_editable<'de, D>(deserializer: D) -> StdResult<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match toml::Value::deserialize(deserializer)? {
+        toml::Value::String(path) => Some(path),
+        toml::Value::Boolean(true) => Some("code".to_string()),
+        toml::Value::Boolean(false) => None,
+        _ => {
+            return Err(serde::de::Error::custom(
+                "editable must be true/false or a directory",
+            ))
+        }
+    })
 }
 
 #[derive(Debug)]
@@ -782,6 +1128,81 @@ pub struct TofuR {
     pub dependency_overrides: Option<HashMap<String, String>>,
     pub additional_packages: Option<HashMap<String, String>>,
     pub use_inside_nix_pkgs: Option<bool>,
+    pub vcs_packages: BTreeMap<String, TofuRVcsPackage>,
+}
+
+#[derive(Debug)]
+pub struct TofuRVcsPackage {
+    pub url: TofuVCS,
+    pub editable_path: Option<String>,
+}
+
+impl TofuR {
+    pub fn has_editable_packages(&self) -> bool {
+        self.vcs_packages
+            .values()
+            .any(|spec| spec.editable_path.is_some())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Julia {
+    pub version: String,
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// The (nix hash augmented) General registry the packages are resolved against
+    pub registry: Option<ParsedVCS>,
+    pub ecosystem_date: Option<String>,
+    /// the `ecosystem_date` the registry rev was resolved for
+    pub registry_date: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct TofuJulia {
+    pub version: String,
+    pub packages: Vec<String>,
+    pub registry: TofuVCS,
+}
+
+impl TofuJulia {
+    /// 1.10 -> `julia_110`
+    pub fn nixpkgs_attribute(&self) -> String {
+        format!("julia_{}", self.version.replace('.', ""))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Node {
+    pub version: String,
+    /// npm package name -> version range, "" for any
+    #[serde(default)]
+    pub packages: BTreeMap<String, String>,
+}
+
+impl Node {
+    /// 20 -> `nodejs_20`
+    pub fn nixpkgs_attribute(&self) -> Result<String> {
+        if self.version.is_empty() || !self.version.chars().all(|c| c.is_ascii_digit()) {
+            bail!(
+                "node.version must be a major version, as in \"20\", was {:?}",
+                self.version
+            );
+        }
+        Ok(format!("nodejs_{}", self.version))
+    }
+}
+
+/// A derivation from a nix file in the project, built with `pkgs.callPackage`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LocalPackage {
+    /// relative to anysnake2.toml
+    pub file: String,
+    /// passed as callPackage's second argument
+    #[serde(default)]
+    pub args: toml::Table,
 }
 
 fn parse_my_date(input: &str) -> Result<jiff::civil::Date> {
@@ -10,7 +10,8 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
-use crate::vcs;
+use crate::nix_expr::{checked_identifier, parse_attr_path, AttrSet, Binding, Expr, Key, StrPart};
+use crate::{r_parsing, vcs};
 use anysnake2::run_without_ctrl_c;
 
 /// captures everything we need to know about an 'input' to our flake.
@@ -60,8 +61,11 @@ fn get_filenames(flake_dir: impl AsRef<Path>, use_generated_file_instead: bool)
 pub struct WriteFlakeResult {
     pub flake_nix_changed: bool,
     pub python_lock_changed: bool,
+    /// files flake.nix refers to (local packages, overlays...) changed
+    pub tracked_files_changed: bool,
 }
 
+#[allow(clippy::too_many_lines)]
 pub fn write_flake(
     flake_dir: impl AsRef<Path>,
     parsed_config: &mut config::TofuConfigToml,
@@ -69,7 +73,6 @@ pub fn write_flake(
     in_non_spec_but_cached_values: &HashMap<String, String>,
     out_non_spec_but_cached_values: &mut HashMap<String, String>,
 ) -> Result<WriteFlakeResult> {
-    let template = std::include_str!("nix/flake_template.nix");
     let flake_dir: &Path = flake_dir.as_ref();
 
     let filenames = get_filenames(flake_dir, use_generated_file_instead);
@@ -77,19 +80,33 @@ pub fn write_flake(
     let old_flake_contents =
         fs::read_to_string(&flake_filename).unwrap_or_else(|_| String::default());
 
-    let mut flake_contents: String = template.to_string();
-
     //various 'collectors'
     let mut inputs: Vec<InputFlake> = Vec::new();
-    let mut definitions: BTreeMap<String, String> = BTreeMap::new();
-    let mut overlays: Vec<String> =
-        vec!["final: prev: { pkgconfig = final.pkg-config;}".to_string()];
+    let mut definitions: BTreeMap<String, Expr> = BTreeMap::new();
+    let mut overlays: Vec<Expr> = vec![Expr::overlay(
+        AttrSet::new()
+            .attr("pkgconfig", Expr::var("final.pkg-config"))
+            .into(),
+    )];
+    // rendered nix expressions, interpolated into the container's script (in this order)
     let mut rust_extensions: Vec<String> = Vec::new();
     let mut nixpkgs_pkgs = BTreeSet::new();
     let mut git_tracked_files = Vec::new();
 
     if let Some(overlay_func) = &parsed_config.nixpkgs.overlay {
-        overlays.push(overlay_func.clone());
+        overlays.push(Expr::raw(overlay_func));
+    }
+    for overlay_file in &parsed_config.nixpkgs.overlays {
+        let content = ex::fs::read_to_string(anysnake2::config_relative_path(overlay_file))
+            .with_context(|| format!("Failed to read nixpkgs overlay {overlay_file}"))?;
+        let nix_path = copy_into_flake(
+            overlay_file,
+            &content,
+            "overlays",
+            flake_dir,
+            &mut git_tracked_files,
+        )?;
+        overlays.push(Expr::var("import").apply([nix_path]));
     }
     //    dbg!(&overlays);
     //let mut nix_pkg_overlays = Vec::new();
@@ -128,9 +145,36 @@ pub fn write_flake(
         &mut overlays,
         &mut nixpkgs_pkgs,
         rust_extensions,
+        flake_dir,
+        &mut git_tracked_files,
+        in_non_spec_but_cached_values,
+        out_non_spec_but_cached_values,
+    )?;
+
+    add_flakes(parsed_config, &mut inputs, &mut nixpkgs_pkgs)?;
+
+    add_julia(
+        parsed_config,
+        &mut inputs,
+        &mut definitions,
+        &mut nixpkgs_pkgs,
     );
 
-    add_flakes(parsed_config, &mut inputs, &mut nixpkgs_pkgs);
+    add_node(
+        parsed_config,
+        &mut definitions,
+        &mut nixpkgs_pkgs,
+        flake_dir,
+        &mut git_tracked_files,
+    )?;
+
+    add_local_packages(
+        parsed_config,
+        &mut overlays,
+        &mut nixpkgs_pkgs,
+        flake_dir,
+        &mut git_tracked_files,
+    )?;
 
     add_r(
         parsed_config,
@@ -138,7 +182,9 @@ pub fn write_flake(
         &mut definitions,
         &mut overlays,
         &mut nixpkgs_pkgs,
-    );
+        in_non_spec_but_cached_values,
+        out_non_spec_but_cached_values,
+    )?;
 
     let python_locks_changed = add_python(
         parsed_config,
@@ -165,31 +211,19 @@ pub fn write_flake(
     flake_contents = flake_contents.replace("#%DEVSHELL_INPUTS%", &dev_shell_inputs);
     */
 
-    definitions.insert(
-        "overlays".to_string(),
-        format!(
-            "[{}]",
-            overlays.iter().map(|ov| format!("({ov})")).join(" ")
-        ),
-    );
+    definitions.insert("overlays".to_string(), Expr::List(overlays));
 
-    flake_contents = insert_nixpkgs_pkgs(&flake_contents, &nixpkgs_pkgs);
-    flake_contents = insert_allow_unfree(
-        &flake_contents,
-        parsed_config.nixpkgs.allow_unfree,
-        parsed_config.nixpkgs.permitted_insecure_packages.as_ref(),
-    );
-    flake_contents = flake_contents
-        .replace("#%INPUT_DEFS%", &format_input_defs(&inputs))
-        .replace("#%INPUTS%", &format_inputs_for_output_arguments(&inputs))
-        .replace("#%DEFINITIONS%#", &format_definitions(&definitions))
-        .replace(
-            "#%DEVSHELL_INPUTS%#",
-            &format_devshell(&parsed_config.dev_shell),
-        );
+    let flake_contents = flake_expr(
+        &inputs,
+        definitions,
+        &nixpkgs_pkgs,
+        &parsed_config.nixpkgs,
+        &parsed_config.dev_shell,
+    )?
+    .to_string();
 
     // pretty print the generated flake
-    flake_contents = nix_format(&flake_contents, flake_dir)?;
+    let flake_contents = nix_format(&flake_contents, flake_dir)?;
 
     /* if !overlays.is_empty() {
         flake_contents = flake_contents.replace(
@@ -211,6 +245,8 @@ pub fn write_flake(
         flake_dir,
     )?;
 
+    let tracked_files_changed = tracked_files_changed(&git_tracked_files, flake_dir)?;
+
     run_git_add(&git_tracked_files, flake_dir)?;
     run_git_commit(flake_dir)?; //after nix 2.23 we will need to commit the flake, possibly. At
                                 //least if we wanted to reference it from another flake
@@ -218,146 +254,315 @@ pub fn write_flake(
         WriteFlakeResult {
             flake_nix_changed,
             python_lock_changed: python_locks_changed,
+            tracked_files_changed,
         }
     })
 }
 
-/// format the list of input flakes for the inputs = {} section of a flake.nix
-fn format_input_defs(inputs: &[InputFlake]) -> String {
-    let mut out = String::default();
+/// The url as nix wants to see it in the inputs section
+fn input_url(fl: &InputFlake) -> String {
+    let url = if fl.url.starts_with("github") && fl.url.matches('/').count() == 3 {
+        //has a branch - but we define a revision, and you can't have both for some reason
+        let mut iter = fl.url.rsplitn(2, '/');
+        iter.next(); // eat the branch
+        iter.collect()
+    } else {
+        fl.url.clone()
+    };
+    match &fl.dir {
+        None => url,
+        Some(dir) if url.contains('?') => format!("{url}&dir={dir}"),
+        Some(dir) => format!("{url}?dir={dir}"),
+    }
+}
+
+/// the inputs = {} section of a flake.nix
+fn input_defs(inputs: &[InputFlake]) -> Expr {
+    let mut defs = AttrSet::rec();
     for fl in inputs {
-        let v_follows: Vec<String> = fl
-            .follows
-            .iter()
-            .map(|x| format!("        inputs.{}.follows = \"{}\";", &x, &x))
-            .collect();
-        let str_follows = v_follows.join("\n");
-        let url = if fl.url.starts_with("github") && fl.url.matches('/').count() == 3 {
-            //has a branch - but we define a revision, and you can't have both for some reason
-            let mut iter = fl.url.rsplitn(2, '/');
-            iter.next(); // eat the branch
-            iter.collect()
-        } else {
-            fl.url.to_string()
-        };
-        let url = match &fl.dir {
-            None => url,
-            Some(dir) => format!("{url}?dir={dir}"),
-        };
-        out.push_str(&format!(
-            "
-    {} = {{
-        url = \"{}\";
-{}
-{}
-    }};",
-            fl.name,
-            url,
-            &str_follows,
-            if fl.is_flake { "" } else { "flake = false;" }
-        ));
+        let mut def = AttrSet::new().attr("url", Expr::str(input_url(fl)));
+        for follows in &fl.follows {
+            def = def.attr_path(["inputs", follows, "follows"], Expr::str(follows));
+        }
+        if !fl.is_flake {
+            def = def.attr("flake", Expr::Bool(false));
+        }
+        defs.push(fl.name.as_str(), def.into());
     }
-    out
+    defs.into()
 }
 
-/// format the list of input flakes for the outputs {self, <arguments>}
-fn format_inputs_for_output_arguments(inputs: &[InputFlake]) -> String {
-    inputs.iter().map(|i| &i.name[..]).join(",\n    ")
+const BASH_EXPR: &str = "let
+    s = builtins.tryEval (pkgs.bashInteractive_5 or pkgs.bashInteractive);
+  in
+    if s.success
+    then s.value
+    else pkgs.bashInteractive";
+
+const FLAKE_INPUTS_FOR_GC_ROOT_EXPR: &str = "pkgs.stdenv.mkDerivation {
+    pname = \"anysnake2-flake-inputs\";
+    version = \"0.1\";
+    unpackPhase = \":\";
+    installPhase = \":\";
+    buildPhase = let
+      str_inputs =
+        builtins.concatStringsSep \"\\n\"
+        (map (key: \"ln -s ${flake_inputs.${key}} ${key}\") (builtins.attrNames flake_inputs));
+    in
+      ''
+        mkdir $out -p
+        cd $out/
+      ''
+      + str_inputs;
+  }";
+
+const DEVSHELL_SHELLHOOK_EXPR: &str = "''
+    export PATH=${packages.default}/rootfs/bin:$PATH;
+    if test -f \"develop_python_path.bash\"; then
+      source \"develop_python_path.bash\"
+    fi
+  ''
+  + (
+    if R_tracked != null
+    then ''
+      export R_LIBS_SITE=${R_tracked}/lib/R/library/
+    ''
+    else \"\"
+  )";
+
+/// The filename of a file we copy into the flake, restricted to what nix path literals allow
+fn nix_path_filename(source: &str) -> Result<String> {
+    let filename = Path::new(source)
+        .file_name()
+        .with_context(|| format!("Path {source} has no filename"))?
+        .to_string_lossy();
+    Ok(filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "._+-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect())
 }
 
-fn format_definitions(definitions: &BTreeMap<String, String>) -> String {
-    let mut res = String::default();
-    for (k, v) in definitions {
-        res.push_str(&format!("     {k} = {v};\n"));
+/// Place a project file into `<flake_dir>/<sub_dir>/<filename>` (only rewriting it on change),
+/// track it, so the flake can see it, and return its nix path
+fn copy_into_flake(
+    source: &str,
+    content: &str,
+    sub_dir: &str,
+    flake_dir: &Path,
+    git_tracked_files: &mut Vec<String>,
+) -> Result<Expr> {
+    let filename = nix_path_filename(source)?;
+    let rel_path = format!("{sub_dir}/{filename}");
+    if git_tracked_files.contains(&rel_path) {
+        bail!("{source}: another file named {filename} is already copied into the flake's {sub_dir}/ - please rename one of them");
+    }
+    let target = flake_dir.join(&rel_path);
+    ex::fs::create_dir_all(target.parent().unwrap())?;
+    if ex::fs::read_to_string(&target).ok().as_deref() != Some(content) {
+        ex::fs::write(&target, content)?;
     }
-    res.trim().to_string()
+    git_tracked_files.push(rel_path.clone());
+    Ok(Expr::path(rel_path))
 }
 
-fn format_devshell(dev_shell: &config::TofuDevShell) -> String {
-    dev_shell.inputs.join(" ")
-}
+/// The complete flake.nix.
+/// `nixpkgs_pkgs` are nix expressions (usually from pkgs), linked into the container
+#[allow(clippy::too_many_lines)]
+fn flake_expr(
+    inputs: &[InputFlake],
+    definitions: BTreeMap<String, Expr>,
+    nixpkgs_pkgs: &BTreeSet<String>,
+    nixpkgs: &config::TofuNixPkgs,
+    dev_shell: &config::TofuDevShell,
+) -> Result<Expr> {
+    let mut nixpkgs_config = AttrSet::new().attr("allowUnfree", Expr::Bool(nixpkgs.allow_unfree));
+    if let Some(permitted) = &nixpkgs.permitted_insecure_packages {
+        nixpkgs_config.push("permittedInsecurePackages", Expr::str_list(permitted));
+    }
+    for (key, value) in &nixpkgs.config {
+        nixpkgs_config.push(
+            key.as_str(),
+            Expr::from_toml(value).with_context(|| format!("in [nixpkgs.config] {key}"))?,
+        );
+    }
+    let mut bindings = vec![Binding::Attr(
+        vec!["pkgs".into()],
+        Expr::var("import").apply([
+            Expr::var("nixpkgs"),
+            AttrSet::new()
+                .inherit(&["system", "overlays"])
+                .attr("config", nixpkgs_config.into())
+                .into(),
+        ]),
+    )];
+    bindings.extend(
+        definitions
+            .into_iter()
+            .map(|(name, value)| Binding::Attr(vec![name.into()], value)),
+    );
 
-fn insert_nixpkgs_pkgs(flake_contents: &str, nixpkgs_pkgs: &BTreeSet<String>) -> String {
-    let str_nixpkgs_pkgs: String = nixpkgs_pkgs
-        .iter()
-        .map(|x| format!("${{{x}}}"))
-        .collect::<Vec<String>>()
-        .join("\n");
-    flake_contents.replace("#%NIXPKGS_PACKAGES%#", &str_nixpkgs_pkgs)
+    //later entries beat earlier entries in terms of /bin symlinks
+    let mut script = vec![
+        StrPart::interp(Expr::var("coreutils")),
+        StrPart::lit("\n"),
+        StrPart::interp(Expr::var("bash")),
+        StrPart::lit("\n"),
+    ];
+    for pkg in nixpkgs_pkgs {
+        script.push(StrPart::interp(Expr::raw(pkg)));
+        script.push(StrPart::lit("\n"));
+    }
+    bindings.push(Binding::Attr(
+        vec!["_args".into()],
+        Expr::with(
+            Expr::var("pkgs"),
+            AttrSet::new()
+                .attr("name", Expr::str("anysnake2_container"))
+                // additional python environments, linked to /envs/<name>
+                .inherit(&["python_envs"])
+                .attr(
+                    "script",
+                    Expr::let_in(
+                        vec![Binding::Attr(vec!["bash".into()], Expr::raw(BASH_EXPR))],
+                        Expr::IndentedStr(script),
+                    ),
+                )
+                .into(),
+        ),
+    ));
+    bindings.push(Binding::Attr(
+        vec!["helpers".into()],
+        Expr::var("import").apply([
+            Expr::path("functions.nix"),
+            AttrSet::new().inherit(&["pkgs"]).into(),
+        ]),
+    ));
+
+    let outputs = AttrSet::rec()
+        .attr(
+            "packages",
+            AttrSet::new()
+                .attr(
+                    "default",
+                    Expr::var("helpers.buildSymlinkImage")
+                        .apply([Expr::var("_args")])
+                        .select(["derivation"]),
+                )
+                .attr(
+                    "oci_image",
+                    Expr::var("helpers.buildOCIimage").apply([Expr::var("_args")]),
+                )
+                .attr(
+                    "flake_inputs_for_gc_root",
+                    Expr::raw(FLAKE_INPUTS_FOR_GC_ROOT_EXPR),
+                )
+                .into(),
+        )
+        .attr(
+            "devShell",
+            Expr::var("pkgs.stdenv.mkDerivation").apply([AttrSet::new()
+                .attr("name", Expr::str("anysnake2-devshell"))
+                .attr("shellHook", Expr::raw(DEVSHELL_SHELLHOOK_EXPR))
+                .attr(
+                    "nativeBuildInputs",
+                    Expr::with(
+                        Expr::var("pkgs"),
+                        Expr::list(dev_shell.inputs.iter().map(Expr::raw)),
+                    ),
+                )
+                .into()]),
+        );
+
+    let mut input_names = vec!["self".to_string()];
+    input_names.extend(inputs.iter().map(|fl| fl.name.clone()));
+    Ok(AttrSet::new()
+        .attr("description", Expr::str("Anysnake2 generated flake"))
+        .attr("inputs", input_defs(inputs))
+        .attr(
+            "outputs",
+            Expr::PatternLambda {
+                names: input_names,
+                at: Some("flake_inputs".to_string()),
+                body: Box::new(Expr::var("flake-utils.lib.eachDefaultSystem").apply([
+                    Expr::lambda("system", Expr::let_in(bindings, outputs.into())),
+                ])),
+            },
+        )
+        .into())
 }
 
-fn insert_allow_unfree(
-    flake_contents: &str,
-    allow_unfree: bool,
-    permitted_insecure_packages: Option<&Vec<String>>,
-) -> String {
-    let flake_contents = flake_contents.replace(
-        "\"%ALLOW_UNFREE%\"",
-        if allow_unfree { "true" } else { "false" },
-    );
-    flake_contents.replace(
-        "\"%PERMITTED_INSECURE_PACKAGES%\"",
-        &(permitted_insecure_packages
-            .as_ref()
-            .map_or_else(String::new, |x| x.join(" "))),
-    )
+fn jupyter_included(parsed_config: &config::TofuConfigToml) -> bool {
+    parsed_config.python.as_ref().is_some_and(|p| {
+        p.packages
+            .iter()
+            .any(|(k, _)| k == "jupyter" || k == "notebook" || k == "jupyterlab")
+    })
 }
 
 fn add_jupyter_kernels(
     parsed_config: &config::TofuConfigToml,
-    definitions: &mut BTreeMap<String, String>,
+    definitions: &mut BTreeMap<String, Expr>,
     nixpkgs_pkgs: &mut BTreeSet<String>,
     rust_extensions: &mut Vec<String>,
 ) {
-    let mut jupyter_kernels = String::new();
-    let jupyter_included = parsed_config.python.as_ref().is_some_and(|p| {
-        p.packages
-            .iter()
-            .any(|(k, _)| k == "jupyter" || k == "notebook" || k == "jupyterlab")
-    });
+    let mut jupyter_kernels: Vec<StrPart> = Vec::new();
+    let jupyter_included = jupyter_included(parsed_config);
     if let Some(r) = &parsed_config.r {
         // install R kernel
         if jupyter_included && r.packages.iter().any(|x| x == "IRkernel") {
-            jupyter_kernels.push_str(
-                "
-                mkdir $out/share/jupyter/kernels/R
-            cp ${R_tracked}/lib/R/library/IRkernel/kernelspec/* $out/share/jupyter/kernels/R -r
-            ",
-            );
+            jupyter_kernels.extend([
+                StrPart::lit("mkdir $out/share/jupyter/kernels/R\ncp "),
+                StrPart::interp(Expr::var("R_tracked")),
+                StrPart::lit(
+                    "/lib/R/library/IRkernel/kernelspec/* $out/share/jupyter/kernels/R -r\n",
+                ),
+            ]);
         }
     }
+    if jupyter_included && parsed_config.julia.is_some() {
+        // add_julia made sure IJulia is in the environment
+        jupyter_kernels.extend([
+            StrPart::lit("mkdir $out/share/jupyter/kernels/julia\necho '{\"argv\": [\""),
+            StrPart::interp(Expr::var("julia_env")),
+            StrPart::lit("/bin/julia\", \"-i\", \"--color=yes\", \"-e\", \"import IJulia; IJulia.run_kernel()\", \"{connection_file}\"], \"display_name\": \"Julia\", \"language\": \"julia\", \"interrupt_mode\": \"message\"}' > $out/share/jupyter/kernels/julia/kernel.json\n"),
+        ]);
+    }
     if parsed_config
         .nixpkgs
         .packages
         .contains(&"evcxr".to_string())
     {
-        jupyter_kernels.push_str(
-            "
-            JUPYTER_PATH=$out/share/jupyter ${pkgs.evcxr}/bin/evcxr_jupyter --install
-        ",
-        );
+        jupyter_kernels.extend([
+            StrPart::lit("JUPYTER_PATH=$out/share/jupyter "),
+            StrPart::interp(Expr::var("pkgs.evcxr")),
+            StrPart::lit("/bin/evcxr_jupyter --install\n"),
+        ]);
         if !rust_extensions.contains(&"rust-src".to_string()) {
             rust_extensions.push("rust-src".to_string());
         }
     }
-    //The python package replaces .../kernel with a symlink.
-    //we restore it here.
-    if !jupyter_kernels.is_empty() && jupyter_included {
-        jupyter_kernels = "
-        ln -s ${python_package}/share/jupyter/kernels/python3 $out/share/jupyter/kernels/python3
-        "
-        .to_string()
-            + &jupyter_kernels;
-    }
     if jupyter_included && !jupyter_kernels.is_empty() {
+        //The python package replaces .../kernel with a symlink.
+        //we restore it here.
+        let mut script = vec![
+            StrPart::lit("mkdir -p $out/share/jupyter/kernels\nln -s "),
+            StrPart::interp(Expr::var("python_package")),
+            StrPart::lit("/share/jupyter/kernels/python3 $out/share/jupyter/kernels/python3\n"),
+        ];
+        script.extend(jupyter_kernels);
         definitions.insert(
             "zzz_jupyter_kernel_drv".to_string(),
-            "pkgs.runCommand \"anysnake2-jupyter-kernels\" {} ''
-                mkdir -p $out/share/jupyter/kernels
-            "
-            .to_string()
-                + &jupyter_kernels
-                + "''",
+            Expr::var("pkgs.runCommand").apply([
+                Expr::str("anysnake2-jupyter-kernels"),
+                AttrSet::new().into(),
+                Expr::IndentedStr(script),
+            ]),
         );
         //must be in the script so it's done before python.
         //since we go reverse...
@@ -379,6 +584,60 @@ fn prep_packages_for_pyproject_toml(
     let mut result = toml::Table::new();
     let mut writeable_to_nix_store_paths = HashMap::new();
     for (name, spec) in input {
+        if !spec.patches.is_empty()
+            && !matches!(spec.source, config::TofuPythonPackageSource::Vcs(_))
+        {
+            // we need a patched local copy, so uv sees the patched metadata
+            let url = match &spec.source {
+                config::TofuPythonPackageSource::Url(url) => url.clone(),
+                config::TofuPythonPackageSource::PyPi { version } => pypi_sdist_url(
+                    name,
+                    version,
+                    in_non_spec_but_cached_values,
+                    out_non_spec_but_cached_values,
+                )?,
+                config::TofuPythonPackageSource::VersionConstraint(constraint) => {
+                    let version = exact_version(constraint).with_context(|| format!("Python package {name} has patches, which requires an exact version (e.g. \"==1.2.3\" or \"pypi:1.2.3\"), but was {constraint:?}"))?;
+                    pypi_sdist_url(
+                        name,
+                        &version,
+                        in_non_spec_but_cached_values,
+                        out_non_spec_but_cached_values,
+                    )?
+                }
+                config::TofuPythonPackageSource::Vcs(_) => unreachable!(),
+            };
+            let (path, sha256) = clone_to_nix_store(
+                &url,
+                "",
+                "url",
+                prefetch_url_store_path,
+                in_non_spec_but_cached_values,
+                out_non_spec_but_cached_values,
+            )?;
+            let writeable_path = copy_for_poetry(
+                &path,
+                name,
+                &sha256,
+                pyproject_toml_path,
+                spec.patch_before_lock.as_ref(),
+                &spec.patches,
+            )?;
+            writeable_to_nix_store_paths.insert(writeable_path.clone(), path.clone());
+            let mut out_map = toml::Table::new();
+            out_map.insert("path".to_string(), writeable_path.into());
+            let src = Expr::var("pkgs.fetchzip")
+                .apply([AttrSet::new()
+                    .attr("url", Expr::str(url))
+                    .attr("hash", Expr::str(&sha256))
+                    .into()])
+                .to_string();
+            spec.anysnake_override_attrs
+                .get_or_insert_with(HashMap::new)
+                .insert("src".to_string(), src);
+            result.insert(name.to_string(), toml::Value::Table(out_map));
+            continue;
+        }
         match &spec.source {
             config::TofuPythonPackageSource::VersionConstraint(version_constraint) => {
                 if version_constraint.contains("==")
@@ -442,19 +701,19 @@ fn prep_packages_for_pyproject_toml(
                             &sha256,
                             pyproject_toml_path,
                             spec.patch_before_lock.as_ref(),
+                            &spec.patches,
                         )?;
                         writeable_to_nix_store_paths.insert(writeable_path.clone(), path.clone());
                         let mut out_map = toml::Table::new();
                         out_map.insert("path".to_string(), writeable_path.into());
-                        let src = format!(
-                            "(
-                            pkgs.fetchFromGitHub {{
-                                    owner = \"{owner}\";
-                                    repo = \"{repo}\";
-                                    rev = \"{rev}\";
-                                    hash = \"{sha256}\";
-                            }})",
-                        );
+                        let src = Expr::var("pkgs.fetchFromGitHub")
+                            .apply([AttrSet::new()
+                                .attr("owner", Expr::str(owner))
+                                .attr("repo", Expr::str(repo))
+                                .attr("rev", Expr::str(rev))
+                                .attr("hash", Expr::str(&sha256))
+                                .into()])
+                            .to_string();
                         spec.anysnake_override_attrs
                             .get_or_insert_with(HashMap::new)
                             .insert("src".to_string(), src);
@@ -485,24 +744,28 @@ fn prep_packages_for_pyproject_toml(
                         &sha256,
                         pyproject_toml_path,
                         spec.patch_before_lock.as_ref(),
+                        &spec.patches,
                     )?;
                     writeable_to_nix_store_paths.insert(writeable_path.clone(), path.clone());
                     let mut out_map = toml::Table::new();
                     out_map.insert("path".to_string(), writeable_path.into());
-                    let src = format!(
-                        "(
-                            pkgs.fetchgit {{
-                                    url = \"{url}\";
-                                    rev = \"{rev}\";
-                                    hash = \"{sha256}\";
-                            }})",
-                    );
+                    let src = Expr::var("pkgs.fetchgit")
+                        .apply([AttrSet::new()
+                            .attr("url", Expr::str(url))
+                            .attr("rev", Expr::str(rev))
+                            .attr("hash", Expr::str(&sha256))
+                            .into()])
+                        .to_string();
                     spec.anysnake_override_attrs
                         .get_or_insert_with(HashMap::new)
                         .insert("src".to_string(), src);
                     result.insert(name.to_string(), toml::Value::Table(out_map));
                 }
-                vcs::TofuVCS::Mercurial { url, rev } => {
+                vcs::TofuVCS::Mercurial {
+                    url,
+                    branch: _,
+                    rev,
+                } => {
                     // poetry does not do mercurial
                     // but nix does.
                     // and poetry does paths.
@@ -524,22 +787,83 @@ fn prep_packages_for_pyproject_toml(
                         &sha256,
                         pyproject_toml_path,
                         spec.patch_before_lock.as_ref(),
+                        &spec.patches,
                     )?;
                     writeable_to_nix_store_paths.insert(writeable_path.clone(), path.clone());
                     out_map.insert("path".to_string(), writeable_path.into());
-                    let src = format!(
-                        "(
-                            pkgs.fetchhg {{
-                                    url = \"{url}\";
-                                    rev = \"{rev}\";
-                                    hash = \"{sha256}\";
-                            }})",
-                    );
+                    let src = Expr::var("pkgs.fetchhg")
+                        .apply([AttrSet::new()
+                            .attr("url", Expr::str(url))
+                            .attr("rev", Expr::str(rev))
+                            .attr("hash", Expr::str(&sha256))
+                            .into()])
+                        .to_string();
                     spec.anysnake_override_attrs
                         .get_or_insert_with(HashMap::new)
                         .insert("src".to_string(), src);
                     result.insert(name.to_string(), toml::Value::Table(out_map));
                 }
+                vcs::TofuVCS::Tarball { url, hash } => {
+                    let (path, _) = clone_to_nix_store(
+                        url,
+                        hash,
+                        "tarball",
+                        prefetch_tarball_store_path,
+                        in_non_spec_but_cached_values,
+                        out_non_spec_but_cached_values,
+                    )?;
+                    let writeable_path = copy_for_poetry(
+                        &path,
+                        name,
+                        hash,
+                        pyproject_toml_path,
+                        spec.patch_before_lock.as_ref(),
+                        &spec.patches,
+                    )?;
+                    writeable_to_nix_store_paths.insert(writeable_path.clone(), path.clone());
+                    let mut out_map = toml::Table::new();
+                    out_map.insert("path".to_string(), writeable_path.into());
+                    spec.anysnake_override_attrs
+                        .get_or_insert_with(HashMap::new)
+                        .insert("src".to_string(), fetch_tarball(url, hash).to_string());
+                    result.insert(name.to_string(), toml::Value::Table(out_map));
+                }
+                vcs::TofuVCS::Forge {
+                    forge,
+                    host,
+                    owner,
+                    repo,
+                    branch: _,
+                    rev,
+                } => {
+                    let (path, sha256, src) = forge_to_nix_store(
+                        *forge,
+                        host,
+                        owner,
+                        repo,
+                        rev,
+                        in_non_spec_but_cached_values,
+                        out_non_spec_but_cached_values,
+                    )?;
+                    let writeable_path = copy_for_poetry(
+                        &path,
+                        name,
+                        &sha256,
+                        pyproject_toml_path,
+                        spec.patch_before_lock.as_ref(),
+                        &spec.patches,
+                    )?;
+                    writeable_to_nix_store_paths.insert(writeable_path.clone(), path.clone());
+                    let mut out_map = toml::Table::new();
+                    out_map.insert("path".to_string(), writeable_path.into());
+                    spec.anysnake_override_attrs
+                        .get_or_insert_with(HashMap::new)
+                        .insert("src".to_string(), src.to_string());
+                    result.insert(name.to_string(), toml::Value::Table(out_map));
+                }
+                vcs::TofuVCS::Path { .. } => {
+                    bail!("python package {name}: path: urls are only supported for flakes. Use editable packages for local code");
+                }
             },
         }
     }
@@ -549,6 +873,36 @@ fn prep_packages_for_pyproject_toml(
     })
 }
 
+/// 'pinned' version constraints ('1.2.3', '==1.2.3') -> '1.2.3'
+fn exact_version(constraint: &str) -> Option<String> {
+    let version = constraint.strip_prefix("==").unwrap_or(constraint).trim();
+    if version.is_empty() || version.contains(['<', '>', '=', '!', '~', '*', ',']) {
+        None
+    } else {
+        Some(version.to_string())
+    }
+}
+
+/// The sdist url for a python package from `PyPI`. Cached, since it never changes.
+fn pypi_sdist_url(
+    name: &SafePythonName,
+    version: &str,
+    in_non_spec_but_cached_values: &HashMap<String, String>,
+    out_non_spec_but_cached_values: &mut HashMap<String, String>,
+) -> Result<String> {
+    let key = format!("pypi_sdist/{name}/{version}/url");
+    let url = match in_non_spec_but_cached_values.get(&key) {
+        Some(url) => url.clone(),
+        None => {
+            anysnake2::util::get_pypi_package_source_url(name.as_str(), Some(version))
+                .with_context(|| format!("Failed to find sdist for {name} {version} on PyPI"))?
+                .url
+        }
+    };
+    out_non_spec_but_cached_values.insert(key, url.clone());
+    Ok(url)
+}
+
 /// poetry needs *writeable* clones of the repos,
 /// because it needs to build egg-infos that write into the checkout
 fn copy_for_poetry(
@@ -557,9 +911,20 @@ fn copy_for_poetry(
     sha256: &str,
     pyproject_toml_path: &Path,
     patch_before_lock: Option<&String>,
+    patches: &[String],
 ) -> Result<String> {
-    let patch_before_lock_sha =
+    let mut patch_before_lock_sha =
         patch_before_lock.map_or_else(|| "None".to_string(), sha256::digest);
+    if !patches.is_empty() {
+        let mut patch_contents = patch_before_lock_sha.clone();
+        for patch in patches {
+            patch_contents.push_str(
+                &ex::fs::read_to_string(anysnake2::config_relative_path(patch))
+                    .with_context(|| format!("Failed to read patch file {patch} for {name}"))?,
+            );
+        }
+        patch_before_lock_sha = sha256::digest(patch_contents);
+    }
 
     let target_path = pyproject_toml_path
         .parent()
@@ -595,6 +960,22 @@ fn copy_for_poetry(
                 bail!("prePoetryPatch failed");
             }
         }
+        for patch in patches {
+            info!("Applying {patch} to {name}");
+            let patch_url = format!("{}#gnupatch", anysnake2::get_outside_nixpkgs_url().unwrap());
+            let abs_patch = anysnake2::config_relative_path(patch).canonicalize()?;
+            let status = Command::new("nix")
+                .args(["shell", &patch_url, "-c", "patch", "-p1", "--batch", "-i"])
+                .arg(&abs_patch)
+                .current_dir(&target_path)
+                .status()
+                .context("Failed to run patch")?;
+            if !status.success() {
+                // so we retry next time
+                ex::fs::remove_dir_all(&target_path)?;
+                bail!("Applying patch {patch} to {name} failed");
+            }
+        }
     }
     //I'd love to return these relative, but since we run ancient-poetry in a tmp dir,
     //this will fail.
@@ -665,6 +1046,7 @@ fn prep_ancient_poetry_pyproject_toml(
     pyproject_toml_path: &Path,
     python_version: &str,
     python_package_definitions: &toml::Table,
+    package_ecosystem_dates: &BTreeMap<String, String>,
 ) -> Result<String> {
     let mut pyproject_toml_contents: toml::Table = format!(
         r#"
@@ -710,6 +1092,23 @@ ancient-date = "{str_date}"
         .as_table_mut()
         .unwrap()
         .insert("dependencies".into(), toml::Value::Array(dependencies));
+    if !package_ecosystem_dates.is_empty() {
+        // not read by ancient-poetry, but it needs to be part of the hash
+        let mut anysnake2 = toml::Table::new();
+        anysnake2.insert(
+            "ecosystem-dates".into(),
+            toml::Value::Table(
+                package_ecosystem_dates
+                    .iter()
+                    .map(|(k, v)| (k.clone(), toml::Value::String(v.clone())))
+                    .collect(),
+            ),
+        );
+        pyproject_toml_contents["tool"]
+            .as_table_mut()
+            .unwrap()
+            .insert("anysnake2".into(), toml::Value::Table(anysnake2));
+    }
     ex::fs::create_dir_all(pyproject_toml_path.parent().unwrap())?;
     let pyproject_contents = pyproject_toml_contents.to_string();
     debug!("Writing {pyproject_toml_path:?}");
@@ -719,6 +1118,54 @@ ancient-date = "{str_date}"
     Ok(pyproject_toml_hash)
 }
 
+/// Packages pinned via 'pypi:<version>' are passed to ancient-poetry as an exclusion list,
+/// i.e. they are not subject to the ecosystem date.
+pub fn ancient_poetry_exclusions(
+    python_packages: &HashMap<SafePythonName, config::TofuPythonPackageDefinition>,
+) -> BTreeMap<String, String> {
+    python_packages
+        .iter()
+        .filter_map(|(name, spec)| match &spec.source {
+            config::TofuPythonPackageSource::PyPi { version } => {
+                Some((name.to_string(), version.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// exact versions for packages with their own `ecosystem_date` (and their lifted dependencies)
+fn ecosystem_date_exclusions(
+    python_packages: &HashMap<SafePythonName, config::TofuPythonPackageDefinition>,
+    str_date: &str,
+    python_version: &str,
+) -> Result<BTreeMap<String, String>> {
+    let mut exclusions = BTreeMap::new();
+    for (name, spec) in python_packages {
+        let Some(package_date) = &spec.ecosystem_date else {
+            continue;
+        };
+        info!("Resolving ecosystem_date = {package_date} for {name}");
+        let constraint = match &spec.source {
+            config::TofuPythonPackageSource::VersionConstraint(constraint) => constraint.as_str(),
+            _ => "",
+        };
+        exclusions.extend(
+            crate::pypi::ecosystem_date_pins(
+                name.as_str(),
+                constraint,
+                package_date,
+                str_date,
+                python_version,
+            )
+            .with_context(|| {
+                format!("Failed to resolve ecosystem_date for python package {name}")
+            })?,
+        );
+    }
+    Ok(exclusions)
+}
+
 #[allow(clippy::too_many_arguments)]
 fn ancient_poetry(
     ancient_poetry: &vcs::TofuVCS,
@@ -734,11 +1181,20 @@ fn ancient_poetry(
     uv_env: Option<&HashMap<String, String>>,
 ) -> Result<()> {
     let str_date = date.strftime("%Y-%m-%d").to_string();
+    let package_ecosystem_dates: BTreeMap<String, String> = python_packages
+        .iter()
+        .filter_map(|(name, spec)| {
+            spec.ecosystem_date
+                .as_ref()
+                .map(|date| (name.to_string(), date.clone()))
+        })
+        .collect();
     let pyproject_toml_hash = prep_ancient_poetry_pyproject_toml(
         &str_date,
         pyproject_toml_path,
         python_version,
         python_package_definitions,
+        &package_ecosystem_dates,
     )?;
 
     let last_hash = ex::fs::read_to_string(pyproject_toml_path.with_extension("sha256"))
@@ -753,14 +1209,12 @@ fn ancient_poetry(
         //todo make configurable
         let full_url = ancient_poetry.to_nix_string();
 
-        let exclusion_list = python_packages
+        let mut exclusions = ecosystem_date_exclusions(python_packages, &str_date, python_version)?;
+        // explicit 'pypi:' pins win
+        exclusions.extend(ancient_poetry_exclusions(python_packages));
+        let exclusion_list = exclusions
             .iter()
-            .filter_map(|(name, spec)| match &spec.source {
-                config::TofuPythonPackageSource::PyPi { version } => {
-                    Some(format!("{name}={version}"))
-                }
-                _ => None,
-            })
+            .map(|(name, version)| format!("{name}={version}"))
             .join(" ");
 
         let mut full_args = vec![
@@ -889,6 +1343,27 @@ fn write_flake_contents(
     res
 }
 
+/// Editing a file flake.nix only refers to by path leaves flake.nix unchanged,
+/// so we keep a hash over all tracked files to notice that
+fn tracked_files_changed(tracked_files: &[String], flake_dir: &Path) -> Result<bool> {
+    let mut combined = String::new();
+    for rel_path in tracked_files.iter().sorted().dedup() {
+        let content = ex::fs::read(flake_dir.join(rel_path)).unwrap_or_default();
+        combined.push_str(rel_path);
+        combined.push(' ');
+        combined.push_str(&sha256::digest(content.as_slice()));
+        combined.push('\n');
+    }
+    let hash = sha256::digest(combined);
+    let hash_path = flake_dir.join("tracked_files.sha256");
+    if fs::read_to_string(&hash_path).ok().as_deref() == Some(hash.as_str()) {
+        Ok(false)
+    } else {
+        fs::write(&hash_path, hash)?;
+        Ok(true)
+    }
+}
+
 fn run_git_add(tracked_files: &[String], flake_dir: &Path) -> Result<()> {
     let output = run_without_ctrl_c(|| {
         Command::new("git")
@@ -926,18 +1401,100 @@ fn run_git_commit(flake_dir: &Path) -> Result<()> {
     }
     Ok(())
 }
+/// A rust program from it's source
+pub fn build_rust_package(name: &str, version: &str, src: Expr, cargo_hash: Expr) -> Expr {
+    Expr::var("pkgs.rustPlatform.buildRustPackage").apply([AttrSet::new()
+        .attr("pname", Expr::str(name))
+        .attr("version", Expr::str(version))
+        .attr("src", src)
+        .attr("cargoHash", cargo_hash)
+        .attr("doCheck", Expr::Bool(false))
+        .into()])
+}
+
+/// `pkgs.fetchCrate` for a crates.io crate
+pub fn fetch_crate(name: &str, version: &str, hash: Expr) -> Expr {
+    Expr::var("pkgs.fetchCrate").apply([AttrSet::new()
+        .attr("pname", Expr::str(name))
+        .attr("version", Expr::str(version))
+        .attr("hash", hash)
+        .into()])
+}
+
+/// components and targets of a rustup toolchain file
+/// (either toml, or the legacy format which is just a channel)
+fn parse_rust_toolchain_file(raw: &str) -> Result<(Vec<String>, Vec<String>)> {
+    if !raw.contains('[') {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    let parsed: toml::Table = toml::from_str(raw).context("Failed to parse toolchain toml")?;
+    let toolchain = parsed
+        .get("toolchain")
+        .and_then(toml::Value::as_table)
+        .context("No [toolchain] in toolchain file")?;
+    let str_list = |key: &str| -> Vec<String> {
+        toolchain
+            .get(key)
+            .and_then(toml::Value::as_array)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter_map(toml::Value::as_str)
+                    .map(ToString::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    Ok((str_list("components"), str_list("targets")))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn add_rust(
     parsed_config: &config::TofuConfigToml,
     inputs: &mut Vec<InputFlake>,
-    definitions: &mut BTreeMap<String, String>,
-    overlays: &mut Vec<String>,
+    definitions: &mut BTreeMap<String, Expr>,
+    overlays: &mut Vec<Expr>,
     nixpkgs_pkgs: &mut BTreeSet<String>,
     rust_extensions: Vec<String>,
-) {
+    flake_dir: &Path,
+    git_tracked_files: &mut Vec<String>,
+    in_non_spec_but_cached_values: &HashMap<String, String>,
+    out_non_spec_but_cached_values: &mut HashMap<String, String>,
+) -> Result<()> {
     if let Some(rust) = &parsed_config.rust {
         nixpkgs_pkgs.insert("stdenv.cc".to_string()); // needed to actually build something with rust
         let mut out_rust_extensions = vec!["rustfmt".to_string(), "clippy".to_string()];
         out_rust_extensions.extend(rust_extensions);
+        out_rust_extensions.extend(rust.components.iter().cloned());
+        let mut rust_targets = rust.targets.clone();
+
+        // the toolchain file is copied into the flake, so nix can read it
+        let toolchain_file_nix_path = match &rust.toolchain_file {
+            Some(toolchain_file) => {
+                let content =
+                    ex::fs::read_to_string(anysnake2::config_relative_path(toolchain_file))
+                        .with_context(|| {
+                            format!("Failed to read rust toolchain file {toolchain_file}")
+                        })?;
+                // overriding replaces the toolchain file's components/targets, so we carry them over
+                let (components, targets) = parse_rust_toolchain_file(&content)
+                    .with_context(|| format!("Failed to parse {toolchain_file}"))?;
+                out_rust_extensions.extend(components);
+                rust_targets.extend(targets);
+                Some(copy_into_flake(
+                    toolchain_file,
+                    &content,
+                    "rust",
+                    flake_dir,
+                    git_tracked_files,
+                )?)
+            }
+            None => None,
+        };
+        out_rust_extensions.sort();
+        out_rust_extensions.dedup();
+        rust_targets.sort();
+        rust_targets.dedup();
 
         inputs.push(InputFlake::new(
             "rust-overlay",
@@ -945,28 +1502,59 @@ fn add_rust(
             None,
             &["nixpkgs"],
         ));
-        overlays.push("import rust-overlay".to_string());
-        let str_rust_extensions: Vec<String> = out_rust_extensions
-            .into_iter()
-            .map(|x| format!("\"{x}\""))
-            .collect();
-        let str_rust_extensions: String = str_rust_extensions.join(" ");
-
+        overlays.push(Expr::var("import").apply([Expr::var("rust-overlay")]));
+
+        let toolchain = match toolchain_file_nix_path {
+            Some(nix_path) => Expr::var("pkgs.rust-bin.fromRustupToolchainFile").apply([nix_path]),
+            None => Expr::var("pkgs.rust-bin").select([
+                rust.channel.as_str(),
+                rust.version.as_str(),
+                rust.profile.as_str(),
+            ]),
+        };
         definitions.insert(
-                "rust".to_string(),
-                format!(
-            "pkgs.rust-bin.stable.\"{}\".minimal.override {{ extensions = [ {str_rust_extensions}]; }}", rust.version,
-        ),
-            );
+            "rust".to_string(),
+            toolchain.select(["override"]).apply([AttrSet::new()
+                .attr("extensions", Expr::str_list(&out_rust_extensions))
+                .attr("targets", Expr::str_list(&rust_targets))
+                .into()]),
+        );
         nixpkgs_pkgs.insert("rust".to_string());
+
+        if !rust.tools.is_empty() {
+            let mut tools = AttrSet::new();
+            for (name, tool) in &rust.tools {
+                let (version, src) = match &tool.source {
+                    config::TofuRustToolSource::Crate { version, hash } => {
+                        (version.clone(), fetch_crate(name, version, Expr::str(hash)))
+                    }
+                    config::TofuRustToolSource::Vcs(url) => {
+                        let (_, rev, _) = url.get_url_rev_branch();
+                        let (_path, src) = vcs_to_nix_store(
+                            url,
+                            in_non_spec_but_cached_values,
+                            out_non_spec_but_cached_values,
+                        )?;
+                        (rev.chars().take(12).collect(), src)
+                    }
+                };
+                tools.push(
+                    name.as_str(),
+                    build_rust_package(name, &version, src, Expr::str(&tool.cargo_hash)),
+                );
+                nixpkgs_pkgs.insert(Expr::var("rust_tools").select([name.as_str()]).to_string());
+            }
+            definitions.insert("rust_tools".to_string(), tools.into());
+        }
     }
+    Ok(())
 }
 
 fn add_flakes(
     parsed_config: &config::TofuConfigToml,
     inputs: &mut Vec<InputFlake>,
     nixpkgs_pkgs: &mut BTreeSet<String>,
-) {
+) -> Result<()> {
     {
         let flakes = &parsed_config.flakes;
         let mut names: Vec<&String> = flakes.keys().collect();
@@ -983,121 +1571,520 @@ fn add_flakes(
                 flake.dir.clone(),
                 &rev_follows[..],
             ));
+            let system = || Key::Dynamic(Expr::var("system"));
             if flake.packages.is_none() {
-                nixpkgs_pkgs.insert(format!(
-                    "({name}.defaultPackage.x86_64-linux or {name}.packages.x86_64-linux.default)",
-                ));
+                nixpkgs_pkgs.insert(
+                    Expr::var(name.as_str())
+                        .select_or(
+                            [Key::from("defaultPackage"), system()],
+                            Expr::var(name.as_str()).select([
+                                Key::from("packages"),
+                                system(),
+                                Key::from("default"),
+                            ]),
+                        )
+                        .to_string(),
+                );
             } else if let Some(pkgs) = &flake.packages {
                 for pkg in pkgs {
-                    nixpkgs_pkgs.insert(format!("{name}.{pkg}"));
+                    // user supplied attribute paths, e.g. packages.${system}.hello
+                    let path = parse_attr_path(&format!("flakes.{name}.packages entry"), pkg)?;
+                    nixpkgs_pkgs.insert(Expr::var(name.as_str()).select(path).to_string());
                 }
             }
         }
     }
+    Ok(())
 }
 
-fn add_r(
+fn add_julia(
     parsed_config: &config::TofuConfigToml,
     inputs: &mut Vec<InputFlake>,
-    definitions: &mut BTreeMap<String, String>,
-    overlays: &mut Vec<String>,
+    definitions: &mut BTreeMap<String, Expr>,
     nixpkgs_pkgs: &mut BTreeSet<String>,
 ) {
-    fn attrset_from_hashmap(attrset: &HashMap<String, String>) -> String {
-        let mut out = String::new();
-        let mut keys: Vec<_> = attrset.keys().collect();
-        keys.sort();
-        for pkg_name in keys {
-            let override_nix_func = attrset.get(pkg_name).unwrap();
-            out.push_str(&format!("\"{pkg_name}\" = ({override_nix_func});"));
+    if let Some(julia) = &parsed_config.julia {
+        let mut registry_input = InputFlake::new("julia_registry", &julia.registry, None, &[]);
+        registry_input.is_flake = false;
+        inputs.push(registry_input);
+
+        let mut packages = julia.packages.clone();
+        if jupyter_included(parsed_config) && !packages.iter().any(|x| x == "IJulia") {
+            packages.push("IJulia".to_string());
         }
-        out
+        packages.sort();
+        definitions.insert(
+            "julia_env".to_string(),
+            Expr::var("pkgs")
+                .select([
+                    julia.nixpkgs_attribute().as_str(),
+                    "withPackages",
+                    "override",
+                ])
+                .apply([AttrSet::new()
+                    .attr("augmentedRegistry", Expr::var("julia_registry"))
+                    .into()])
+                .apply([Expr::str_list(&packages)]),
+        );
+        nixpkgs_pkgs.insert("julia_env".to_string());
     }
+}
 
-    if let Some(r_config) = &parsed_config.r {
-        inputs.push(InputFlake::new("nixR", &r_config.url, None, &[]));
-
-        let r_override_args = r_config
+/// `[packages.<name>]` nix files, exposed as `pkgs.<name>` via an overlay
+fn add_local_packages(
+    parsed_config: &config::TofuConfigToml,
+    overlays: &mut Vec<Expr>,
+    nixpkgs_pkgs: &mut BTreeSet<String>,
+    flake_dir: &Path,
+    git_tracked_files: &mut Vec<String>,
+) -> Result<()> {
+    if parsed_config.packages.is_empty() {
+        return Ok(());
+    }
+    let mut packages = AttrSet::new();
+    for (name, package) in &parsed_config.packages {
+        checked_identifier("[packages] name", name)?;
+        let content = ex::fs::read_to_string(anysnake2::config_relative_path(&package.file))
+            .with_context(|| format!("Failed to read {} for packages.{name}", package.file))?;
+        let nix_path = copy_into_flake(
+            &package.file,
+            &content,
+            "packages",
+            flake_dir,
+            git_tracked_files,
+        )?;
+        packages.push(
+            name.as_str(),
+            Expr::var("final.callPackage").apply([
+                nix_path,
+                Expr::from_toml(&toml::Value::Table(package.args.clone()))
+                    .with_context(|| format!("in [packages.{name}] args"))?,
+            ]),
+        );
+        nixpkgs_pkgs.insert(Expr::var("pkgs").select([name.as_str()]).to_string());
+    }
+    overlays.push(Expr::overlay(packages.into()));
+    Ok(())
+}
+
+/// write node/package.json, (re)lock it with npm if it changed,
+/// and build the `node_modules` with nixpkgs' `buildNpmPackage` / `importNpmLock`
+fn add_node(
+    parsed_config: &config::TofuConfigToml,
+    definitions: &mut BTreeMap<String, Expr>,
+    nixpkgs_pkgs: &mut BTreeSet<String>,
+    flake_dir: &Path,
+    git_tracked_files: &mut Vec<String>,
+) -> Result<()> {
+    if let Some(node) = &parsed_config.node {
+        let nodejs = node.nixpkgs_attribute()?;
+        let node_dir = flake_dir.join("node");
+        ex::fs::create_dir_all(&node_dir)?;
+        let package_json_path = node_dir.join("package.json");
+        let package_lock_path = node_dir.join("package-lock.json");
+
+        let dependencies: serde_json::Map<String, serde_json::Value> = node
+            .packages
+            .iter()
+            .map(|(name, range)| {
+                let range = if range.is_empty() { "*" } else { range };
+                (name.clone(), range.into())
+            })
+            .collect();
+        let package_json = serde_json::json!({
+            "name": "anysnake2-node-env",
+            "version": "0.1.0",
+            "description": "This file is generated by anysnake2. Do not edit it manually.",
+            "private": true,
+            "engines": {"node": node.version},
+            "dependencies": dependencies,
+        });
+        let package_json = serde_json::to_string_pretty(&package_json)? + "\n";
+        let package_json_hash = sha256::digest(package_json.as_str());
+        ex::fs::write(&package_json_path, &package_json)?;
+
+        let last_hash = ex::fs::read_to_string(package_json_path.with_extension("sha256"))
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if package_json_hash != last_hash || !package_lock_path.exists() {
+            info!("Locking node packages");
+            if package_lock_path.exists() {
+                ex::fs::remove_file(&package_lock_path)?;
+            }
+            let full_args = [
+                "shell".to_string(),
+                format!("{}#{nodejs}", parsed_config.nixpkgs.url.to_nix_string()),
+                "-c".to_string(),
+                "npm".to_string(),
+                "install".to_string(),
+                "--package-lock-only".to_string(),
+                "--ignore-scripts".to_string(),
+                "--no-audit".to_string(),
+                "--no-fund".to_string(),
+            ];
+            debug!("running npm: nix {}", full_args.join(" "));
+            let out = Command::new("nix")
+                .args(full_args)
+                .current_dir(&node_dir)
+                .status()?;
+            if !out.success() {
+                bail!("npm locking failed, returncode: {:?}", out.code());
+            }
+            ex::fs::write(
+                package_json_path.with_extension("sha256"),
+                package_json_hash,
+            )?;
+        } else {
+            debug!("Skipping npm lock - package.json matches last run");
+        }
+        git_tracked_files.push("node/package.json".to_string());
+        git_tracked_files.push("node/package-lock.json".to_string());
+
+        definitions.insert(
+            "node_env".to_string(),
+            Expr::var("pkgs.buildNpmPackage").apply([AttrSet::new()
+                .attr("pname", Expr::str("anysnake2-node-env"))
+                .attr("version", Expr::str("0.1.0"))
+                .attr("src", Expr::path("node"))
+                .attr("nodejs", Expr::var("pkgs").select([nodejs.as_str()]))
+                .attr(
+                    "npmDeps",
+                    Expr::var("pkgs.importNpmLock")
+                        .apply([AttrSet::new().attr("npmRoot", Expr::path("node")).into()]),
+                )
+                .attr(
+                    "npmConfigHook",
+                    Expr::var("pkgs.importNpmLock.npmConfigHook"),
+                )
+                .attr("dontNpmBuild", Expr::Bool(true))
+                .attr(
+                    "installPhase",
+                    Expr::IndentedStr(vec![StrPart::lit(
+                        "mkdir -p $out/lib $out/bin
+cp -r node_modules $out/lib/node_modules
+if [ -d $out/lib/node_modules/.bin ]; then
+  for f in $out/lib/node_modules/.bin/*; do
+    ln -s $(readlink -f $f) $out/bin/$(basename $f)
+  done
+fi
+",
+                    )]),
+                )
+                .into()]),
+        );
+        nixpkgs_pkgs.insert(nodejs);
+        nixpkgs_pkgs.insert("node_env".to_string());
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_lines)]
+fn add_r(
+    parsed_config: &config::TofuConfigToml,
+    inputs: &mut Vec<InputFlake>,
+    definitions: &mut BTreeMap<String, Expr>,
+    overlays: &mut Vec<Expr>,
+    nixpkgs_pkgs: &mut BTreeSet<String>,
+    in_non_spec_but_cached_values: &HashMap<String, String>,
+    out_non_spec_but_cached_values: &mut HashMap<String, String>,
+) -> Result<()> {
+    /// user supplied nix code per package
+    fn attrset_from_hashmap(attrset: &HashMap<String, String>) -> BTreeMap<String, Expr> {
+        attrset
+            .iter()
+            .map(|(pkg_name, override_nix_func)| (pkg_name.clone(), Expr::raw(override_nix_func)))
+            .collect()
+    }
+
+    if let Some(r_config) = &parsed_config.r {
+        inputs.push(InputFlake::new("nixR", &r_config.url, None, &[]));
+
+        let r_override_args = r_config
             .override_attrs
             .as_ref()
-            .map_or(String::new(), attrset_from_hashmap);
+            .map(attrset_from_hashmap)
+            .unwrap_or_default();
         let r_dependency_overrides = r_config
             .dependency_overrides
             .as_ref()
-            .map_or(String::new(), attrset_from_hashmap);
-        let r_additional_packages = r_config
+            .map(attrset_from_hashmap)
+            .unwrap_or_default();
+        let mut additional_packages = r_config
             .additional_packages
             .as_ref()
-            .map_or(String::new(), attrset_from_hashmap);
-
+            .map(attrset_from_hashmap)
+            .unwrap_or_default();
         let mut r_pkg_list: Vec<String> =
             r_config.packages.iter().map(ToString::to_string).collect();
-        if let Some(additional_packages) = &r_config.additional_packages {
-            for pkg_ver in additional_packages.keys() {
-                let (pkg, _ver) = pkg_ver
-                    .split_once('_')
-                    .expect("R.additional_packages key did not conform to 'name_version' schema");
-                r_pkg_list.push(pkg.to_string());
+
+        // vcs packages are fetched once to read their DESCRIPTION,
+        // so we know their version and can request their dependencies from nixR.
+        // Editable ones only contribute their dependencies (and devtools for load_all),
+        // the package itself is bound into the container from its checkout.
+        for (name, spec) in &r_config.vcs_packages {
+            let (path, src) = vcs_to_nix_store(
+                &spec.url,
+                in_non_spec_but_cached_values,
+                out_non_spec_but_cached_values,
+            )?;
+            let description_path = PathBuf::from(&path).join("DESCRIPTION");
+            let description = r_parsing::parse_description(
+                &ex::fs::read_to_string(&description_path)
+                    .with_context(|| format!("R package {name} has no DESCRIPTION file"))?,
+            )
+            .with_context(|| format!("Failed to parse DESCRIPTION of R package {name}"))?;
+            if &description.package != name {
+                bail!(
+                    "R.vcs_packages.{name}: the DESCRIPTION names this package '{}'",
+                    description.package
+                );
             }
+            r_pkg_list.extend(description.dependencies);
+            if spec.editable_path.is_some() {
+                r_pkg_list.push("devtools".to_string());
+            } else {
+                additional_packages.insert(
+                    format!("{name}_{}", description.version),
+                    AttrSet::new().attr("src", src).into(),
+                );
+            }
+        }
+
+        for pkg_ver in additional_packages.keys() {
+            let (pkg, _ver) = pkg_ver
+                .split_once('_')
+                .expect("R.additional_packages key did not conform to 'name_version' schema");
+            r_pkg_list.push(pkg.to_string());
         }
         //remove duplicates
         r_pkg_list.sort();
         r_pkg_list.dedup();
 
         let nix_nix_pkgs = if r_config.use_inside_nix_pkgs.unwrap_or(true) {
-            "pkgs"
+            Expr::var("pkgs")
         } else {
-            "null"
+            Expr::Null
         };
 
-        let r_packages = format!(
-            "
-                nixR.R_by_date {{
-                    date = \"{}\" ;
-                    r_pkg_names = [{}];
-                    nix_pkgs_pkgs = {nix_nix_pkgs};
-                    packageOverrideAttrs = {{ {} }};
-                    r_dependency_overrides = {{ {} }};
-                    additional_packages = {{ {} }};
-                }}
-                ",
-            &r_config.date,
-            r_pkg_list.iter().map(|x| format!("\"{x}\"")).join(" "),
-            r_override_args,
-            r_dependency_overrides,
-            r_additional_packages
-        );
+        let r_packages = Expr::var("nixR.R_by_date").apply([AttrSet::new()
+            .attr("date", Expr::str(&r_config.date))
+            .attr("r_pkg_names", Expr::str_list(&r_pkg_list))
+            .attr("nix_pkgs_pkgs", nix_nix_pkgs)
+            .attr(
+                "packageOverrideAttrs",
+                r_override_args.into_iter().collect::<AttrSet>().into(),
+            )
+            .attr(
+                "r_dependency_overrides",
+                r_dependency_overrides
+                    .into_iter()
+                    .collect::<AttrSet>()
+                    .into(),
+            )
+            .attr(
+                "additional_packages",
+                additional_packages.into_iter().collect::<AttrSet>().into(),
+            )
+            .into()]);
         definitions.insert("R_tracked".to_string(), r_packages);
-        overlays.push(
-            "(final: prev: {
-                R = R_tracked // {meta = { platforms=prev.R.meta.platforms;};};
-                rPackages = R_tracked.rPackages;
-                }) "
-            .to_string(),
-        );
+        overlays.push(Expr::overlay(
+            AttrSet::new()
+                .attr(
+                    "R",
+                    Expr::var("R_tracked").bin_op(
+                        "//",
+                        AttrSet::new()
+                            .attr(
+                                "meta",
+                                AttrSet::new()
+                                    .attr("platforms", Expr::var("prev.R.meta.platforms"))
+                                    .into(),
+                            )
+                            .into(),
+                    ),
+                )
+                .attr("rPackages", Expr::var("R_tracked.rPackages"))
+                .into(),
+        ));
 
         nixpkgs_pkgs.insert("(builtins.elemAt R.buildInputs 0)".to_string()); // that's the overlayed R.
         nixpkgs_pkgs.insert("R".to_string()); // that's the overlayed R.
     } else {
-        definitions.insert("R_tracked".to_string(), "null".to_string());
+        definitions.insert("R_tracked".to_string(), Expr::Null);
     }
+    Ok(())
+}
+
+/// Fetch a (tofued) vcs source into the nix store.
+/// Returns the store path and the nix expression that fetches the same source.
+pub fn vcs_to_nix_store(
+    url: &vcs::TofuVCS,
+    in_non_spec_but_cached_values: &HashMap<String, String>,
+    out_non_spec_but_cached_values: &mut HashMap<String, String>,
+) -> Result<(String, Expr)> {
+    Ok(match url {
+        vcs::TofuVCS::GitHub {
+            owner,
+            repo,
+            branch: _,
+            rev,
+        } => {
+            let (path, sha256) = clone_to_nix_store(
+                &format!("github:{owner}/{repo}"),
+                rev,
+                "git",
+                prefetch_github_store_path,
+                in_non_spec_but_cached_values,
+                out_non_spec_but_cached_values,
+            )?;
+            let src = Expr::var("pkgs.fetchFromGitHub").apply([AttrSet::new()
+                .attr("owner", Expr::str(owner))
+                .attr("repo", Expr::str(repo))
+                .attr("rev", Expr::str(rev))
+                .attr("hash", Expr::str(sha256))
+                .into()]);
+            (path, src)
+        }
+        vcs::TofuVCS::Git {
+            url,
+            branch: _,
+            rev,
+        } => {
+            let (path, sha256) = clone_to_nix_store(
+                url,
+                rev,
+                "git",
+                prefetch_git_store_path,
+                in_non_spec_but_cached_values,
+                out_non_spec_but_cached_values,
+            )?;
+            let src = Expr::var("pkgs.fetchgit").apply([AttrSet::new()
+                .attr("url", Expr::str(url))
+                .attr("rev", Expr::str(rev))
+                .attr("hash", Expr::str(sha256))
+                .into()]);
+            (path, src)
+        }
+        vcs::TofuVCS::Mercurial {
+            url,
+            branch: _,
+            rev,
+        } => {
+            let (path, sha256) = clone_to_nix_store(
+                url,
+                rev,
+                "mercurial",
+                prefetch_hg_store_path,
+                in_non_spec_but_cached_values,
+                out_non_spec_but_cached_values,
+            )?;
+            let src = Expr::var("pkgs.fetchhg").apply([AttrSet::new()
+                .attr("url", Expr::str(url))
+                .attr("rev", Expr::str(rev))
+                .attr("hash", Expr::str(sha256))
+                .into()]);
+            (path, src)
+        }
+        vcs::TofuVCS::Tarball { url, hash } => {
+            let (path, _) = clone_to_nix_store(
+                url,
+                hash,
+                "tarball",
+                prefetch_tarball_store_path,
+                in_non_spec_but_cached_values,
+                out_non_spec_but_cached_values,
+            )?;
+            (path, fetch_tarball(url, hash))
+        }
+        vcs::TofuVCS::Forge {
+            forge,
+            host,
+            owner,
+            repo,
+            branch: _,
+            rev,
+        } => {
+            let (path, _, src) = forge_to_nix_store(
+                *forge,
+                host,
+                owner,
+                repo,
+                rev,
+                in_non_spec_but_cached_values,
+                out_non_spec_but_cached_values,
+            )?;
+            (path, src)
+        }
+        vcs::TofuVCS::Path { .. } => {
+            bail!("{url}: path: urls are only supported for flakes");
+        }
+    })
+}
+
+/// Forges are fetched as the archive tarball they offer (no full clone).
+/// Returns the store path, the hash and the matching nixpkgs fetchFrom* expression.
+fn forge_to_nix_store(
+    forge: vcs::Forge,
+    host: &str,
+    owner: &str,
+    repo: &str,
+    rev: &str,
+    in_non_spec_but_cached_values: &HashMap<String, String>,
+    out_non_spec_but_cached_values: &mut HashMap<String, String>,
+) -> Result<(String, String, Expr)> {
+    let (path, sha256) = clone_to_nix_store(
+        &forge.archive_url(host, owner, repo, rev),
+        rev,
+        "tarball",
+        |url, _rev| prefetch_tarball_store_path(url, ""),
+        in_non_spec_but_cached_values,
+        out_non_spec_but_cached_values,
+    )?;
+    let (fetcher, domain) = match forge {
+        vcs::Forge::GitLab => ("pkgs.fetchFromGitLab", host),
+        // fetchFromSourcehut wants 'sr.ht' and fetches from git.sr.ht
+        vcs::Forge::SourceHut => (
+            "pkgs.fetchFromSourcehut",
+            host.strip_prefix("git.").unwrap_or(host),
+        ),
+        vcs::Forge::Forgejo => ("pkgs.fetchFromGitea", host),
+    };
+    let src = Expr::var(fetcher).apply([AttrSet::new()
+        .attr("domain", Expr::str(domain))
+        .attr("owner", Expr::str(owner.replace("%2F", "/")))
+        .attr("repo", Expr::str(repo))
+        .attr("rev", Expr::str(rev))
+        .attr("hash", Expr::str(&sha256))
+        .into()]);
+    Ok((path, sha256, src))
+}
+
+fn fetch_tarball(url: &str, hash: &str) -> Expr {
+    Expr::var("pkgs.fetchzip").apply([AttrSet::new()
+        .attr("url", Expr::str(url))
+        .attr("hash", Expr::str(hash))
+        .into()])
 }
 
 fn format_overrides(
     python_packages: &HashMap<SafePythonName, config::TofuPythonPackageDefinition>,
-) -> (Vec<String>, Vec<String>) {
-    fn to_vec(overrides: HashMap<String, HashMap<String, String>>) -> Vec<String> {
-        let mut out = Vec::new();
-        for (name, key_value) in overrides {
-            let mut here = format!("{name} = prev.{name}.overrideAttrs (old: {{");
-            for (key, value) in itertools::sorted(key_value) {
-                here.push_str(&format!("{key} = {value};"));
-            }
-            here.push_str("});");
-            out.push(here);
-        }
-        out.sort();
-        out
+) -> (AttrSet, AttrSet) {
+    /// the values are nix code
+    fn to_attrs(overrides: HashMap<String, HashMap<String, String>>) -> AttrSet {
+        overrides
+            .into_iter()
+            .sorted_by(|a, b| a.0.cmp(&b.0))
+            .map(|(name, key_value)| {
+                let attrs: AttrSet = itertools::sorted(key_value)
+                    .map(|(key, value)| (key, Expr::raw(value)))
+                    .collect();
+                let value = Expr::var("prev")
+                    .select([name.as_str(), "overrideAttrs"])
+                    .apply([Expr::lambda("old", attrs.into())]);
+                (name, value)
+            })
+            .collect()
     }
 
     let mut anysnake_overrides: HashMap<String, HashMap<String, String>> = HashMap::new();
@@ -1106,16 +2093,18 @@ fn format_overrides(
     for (name, spec) in python_packages {
         if let Some(build_systems) = &spec.build_systems {
             let target = anysnake_overrides.entry(name.to_string()).or_default();
-            let str_build_systems = build_systems
+            let build_systems: AttrSet = build_systems
                 .iter()
-                .map(|x| format!("{x} = [];"))
-                .collect::<Vec<String>>()
-                .join(" ");
+                .map(|x| (x.as_str(), Expr::list(Vec::<Expr>::new())))
+                .collect();
             target.insert(
                 "nativeBuildInputs".to_string(),
-                format!(
-                    "old.nativeBuildInputs ++ ( final.resolveBuildSystem {{ {str_build_systems} }} ) ",
-                ),
+                Expr::var("old.nativeBuildInputs")
+                    .bin_op(
+                        "++",
+                        Expr::var("final.resolveBuildSystem").apply([build_systems.into()]),
+                    )
+                    .to_string(),
             );
         }
         for (key, value) in &spec.override_attrs {
@@ -1164,7 +2153,7 @@ fn format_overrides(
         );
     }
 
-    (to_vec(anysnake_overrides), to_vec(user_overrides))
+    (to_attrs(anysnake_overrides), to_attrs(user_overrides))
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -1172,10 +2161,10 @@ fn format_overrides(
 fn add_python(
     parsed_config: &mut config::TofuConfigToml,
     inputs: &mut Vec<InputFlake>,
-    definitions: &mut BTreeMap<String, String>,
+    definitions: &mut BTreeMap<String, Expr>,
     nixpkgs_pkgs: &mut BTreeSet<String>,
     git_tracked_files: &mut Vec<String>,
-    overlays: &mut Vec<String>,
+    overlays: &mut Vec<Expr>,
     pyproject_toml_path: &Path,
     uv_lock_path: &Path,
     flake_dir: &Path,
@@ -1184,6 +2173,7 @@ fn add_python(
 ) -> Result<bool> {
     //ex::fs::create_dir_all(poetry_lock.parent().unwrap())?;
     let mut changed = false;
+    definitions.insert("python_envs".to_string(), AttrSet::new().into());
     match &mut parsed_config.python {
         Some(python) => {
             let original_pyproject_toml =
@@ -1191,49 +2181,31 @@ fn add_python(
             let original_poetry_lock =
                 ex::fs::read_to_string(uv_lock_path).unwrap_or_else(|_| String::new());
 
-            if !Regex::new(r"^\d+\.\d+$").unwrap().is_match(&python.version) {
-                bail!(
-                            format!("Python version must be x.y (not x.y.z, z is given by nixpkgs version). Was '{}'", &python.version));
-            }
-            let python_major_minor = format!("python{}", python.version.replace('.', ""));
-
-            let prep_result = prep_packages_for_pyproject_toml(
-                &mut python.packages,
-                in_non_spec_but_cached_values,
-                out_non_spec_but_cached_values,
-                pyproject_toml_path,
-            )?;
-            let mut out_python_packages = prep_result.pyproject_fragment;
-            if python.has_editable_packages() && !out_python_packages.contains_key("pip") {
-                out_python_packages
-                    .insert("pip".to_string(), toml::Value::String(">0".to_string()));
-            }
+            let python_major_minor = nixpkgs_python_attribute(&python.version)?;
 
-            //out_python_packages.sort();
-            let python_version = python.version.clone();
+            let has_editable_packages = python.has_editable_packages();
             let ecosystem_date = python.parsed_ecosystem_date()?;
-
-            ancient_poetry(
-                &parsed_config.ancient_poetry,
-                &parsed_config.nixpkgs,
-                &parsed_config.uv2nix.source,
-                &python.packages,
-                &out_python_packages,
-                pyproject_toml_path,
-                uv_lock_path,
-                &python_version,
-                &python_major_minor,
+            let locker = PythonLocker {
+                ancient_poetry: &parsed_config.ancient_poetry,
+                nixpkgs: &parsed_config.nixpkgs,
+                uv_flake: &parsed_config.uv2nix.source,
+                flake_dir,
+                in_non_spec_but_cached_values,
+            };
+            let extend_build_systems = locker.lock(
+                &python.version,
                 ecosystem_date,
+                &mut python.packages,
                 python.uv_lock_env.as_ref(),
+                has_editable_packages,
+                pyproject_toml_path,
+                uv_lock_path,
+                &flake_dir.join("uv"),
+                "uv_rewritten",
+                out_non_spec_but_cached_values,
+                git_tracked_files,
             )?;
 
-            rewrite_poetry(flake_dir, &prep_result.writeable_to_nix_store_paths)?;
-            let extend_build_systems = check_if_setuptools_needs_expansion(uv_lock_path)?;
-            write_setup_cfg(flake_dir, ecosystem_date, git_tracked_files)?;
-
-            let (local_anysnake_overrides, local_user_overrides) = //todo: override_attrs...
-                format_overrides(&python.packages);
-
             inputs.push(InputFlake::new(
                 "uv2nix",
                 &parsed_config.uv2nix.source,
@@ -1241,8 +2213,7 @@ fn add_python(
                 &[],
             ));
             inputs.push(InputFlake::new(
-                "
-            pyproject-build-systems",
+                "pyproject-build-systems",
                 &parsed_config.pyproject_build_systems,
                 None,
                 &["uv2nix", "nixpkgs"],
@@ -1254,101 +2225,123 @@ fn add_python(
                 &[],
             ));
 
-            let prefer_wheels = parsed_config.uv2nix.prefer_wheels;
-            let source_preference = if prefer_wheels { "wheel" } else { "sdist" };
+            let source_preference =
+                config::SourcePreference::from_prefer_wheels(parsed_config.uv2nix.prefer_wheels);
             definitions.insert(
                 "pyproject-nix".to_string(),
-                "uv2nix.inputs.pyproject-nix".to_string(),
-            );
-            let workspace = "uv_rewritten";
-            definitions.insert(
-                "workspace".to_string(),
-                format!("uv2nix.lib.workspace.loadWorkspace {{workspaceRoot = ./{workspace};}}",),
-            );
-
-            definitions.insert(
-                "overlay".to_string(),
-                format!("workspace.mkPyprojectOverlay {{ sourcePreference = \"{source_preference}\"; }}")
-            );
-
-            definitions.insert(
-                "fix_resolve_build_systems".to_string(),
-                (if extend_build_systems {
-                    "
-                    (final: prev: {
-                        resolveBuildSystem =
-                        arg: prev.resolveBuildSystem (arg
-                        //
-                        # only if setuptools is in arg
-                        (
-                          if arg.setuptools or null != null
-                          then {wheel = [];}
-                          else {}
-                        ));
-                    })"
-                } else {
-                    "(final: prev: {} )"
-                })
-                .to_string(),
-            );
-
-            definitions.insert(
-                "local_anysnake_overrides".to_string(),
-                format!(
-                    "(final: prev: {{ {} }})",
-                    local_anysnake_overrides.join("\n")
-                ),
-            );
-            definitions.insert(
-                "local_user_overrides".to_string(),
-                format!("(final: prev: {{ {} }})", local_user_overrides.join("\n")),
-            );
-
-            definitions.insert(
-                "pyprojectOverrides ".to_string(),
-                "[ fix_resolve_build_systems
-                    (uv2nix_override_collection.overrides pkgs)
-                    local_anysnake_overrides
-                    local_user_overrides
-                ]"
-                .to_string(),
-            ); //todo: insert override_attrs here.
-            definitions.insert(
-                "interpreter".to_string(),
-                format!("pkgs.{python_major_minor}"),
+                Expr::var("uv2nix.inputs.pyproject-nix"),
             );
             definitions.insert(
                 "spec".to_string(),
-                "{anysnake2-to-ancient-poetry-uv = []; }".to_string(),
+                AttrSet::new()
+                    .attr(
+                        "anysnake2-to-ancient-poetry-uv",
+                        Expr::list(Vec::<Expr>::new()),
+                    )
+                    .into(),
             );
-            // Use base package set from pyproject.nix builders
-            definitions.insert(
-                "pythonSet".to_string(),
-                "(pkgs.callPackage pyproject-nix.build.packages {
-                    python = interpreter;
-        }).overrideScope
-          (
-            pkgs.lib.composeManyExtensions ([
-              pyproject-build-systems.overlays.default
-              overlay ] ++ 
-              pyprojectOverrides)
-          )"
-                .to_string(),
+            add_python_env_definitions(
+                definitions,
+                "",
+                "uv_rewritten",
+                &python_major_minor,
+                source_preference,
+                extend_build_systems,
+                &python.packages,
             );
-            //Override host packages with build fixups
-            /* definitions.insert(
-                "pythonSet".to_string(),
-                "pythonSet'.pythonPkgsHostHost.overrideScope pyprojectOverrides".to_string(),
-            ); */
 
+            // python_package is what ends up in the rootfs /bin.
+            // If another env is the default, [python] is linked to /envs/python instead
+            let default_env = python.default_env().map(ToString::to_string);
+            let main_venv = if default_env.is_some() {
+                "python_env_python"
+            } else {
+                "python_package"
+            };
             definitions.insert(
-                "python_package".to_string(),
-                "pythonSet.mkVirtualEnv \"anysnake2-venv\" spec".to_string(),
+                main_venv.to_string(),
+                Expr::var("pythonSet.mkVirtualEnv")
+                    .apply([Expr::str("anysnake2-venv"), Expr::var("spec")]),
             );
             nixpkgs_pkgs.insert("python_package".to_string());
 
-            git_tracked_files.push("uv_rewritten/uv.lock".to_string());
-            git_tracked_files.push("uv_rewritten/pyproject.toml".to_string());
+            // the additional environments are not part of the rootfs /bin
+            // (their pyvenv.cfg would collide), but linked to /envs/<name>
+            let mut python_envs = AttrSet::new();
+            if let Some(default_env) = &default_env {
+                python_envs.push("python", Expr::var(main_venv));
+                definitions.insert(
+                    "python_package".to_string(),
+                    Expr::var(format!("python_env_{default_env}")),
+                );
+            }
+            for (env_name, env) in &mut python.envs {
+                if env
+                    .packages
+                    .values()
+                    .any(|spec| spec.editable_path.is_some())
+                {
+                    bail!("Editable python packages are only supported in the default [python] environment, not in [python.envs.{env_name}]");
+                }
+                let env_major_minor = nixpkgs_python_attribute(&env.version)
+                    .with_context(|| format!("in [python.envs.{env_name}]"))?;
+                let lock_dir = pyproject_toml_path
+                    .parent()
+                    .unwrap()
+                    .join(env_name.as_str());
+                let env_pyproject_toml_path = lock_dir.join("pyproject.toml");
+                let env_uv_lock_path = lock_dir.join("uv.lock");
+                let original_pyproject_toml = ex::fs::read_to_string(&env_pyproject_toml_path)
+                    .unwrap_or_else(|_| String::new());
+                let original_uv_lock =
+                    ex::fs::read_to_string(&env_uv_lock_path).unwrap_or_else(|_| String::new());
+
+                let rewritten_dir = format!("uv_rewritten/{env_name}");
+                let extend_build_systems = locker
+                    .lock(
+                        &env.version,
+                        env.parsed_ecosystem_date()?,
+                        &mut env.packages,
+                        env.uv_lock_env.as_ref(),
+                        false,
+                        &env_pyproject_toml_path,
+                        &env_uv_lock_path,
+                        &lock_dir,
+                        &rewritten_dir,
+                        out_non_spec_but_cached_values,
+                        git_tracked_files,
+                    )
+                    .with_context(|| format!("Failed to lock [python.envs.{env_name}]"))?;
+
+                let suffix = format!("_{env_name}");
+                add_python_env_definitions(
+                    definitions,
+                    &suffix,
+                    &rewritten_dir,
+                    &env_major_minor,
+                    source_preference,
+                    extend_build_systems,
+                    &env.packages,
+                );
+                definitions.insert(
+                    format!("python_env{suffix}"),
+                    Expr::var(format!("pythonSet{suffix}.mkVirtualEnv")).apply([
+                        Expr::str(format!("anysnake2-venv-{env_name}")),
+                        Expr::var("spec"),
+                    ]),
+                );
+                python_envs.push(env_name.as_str(), Expr::var(format!("python_env{suffix}")));
+
+                if ex::fs::read_to_string(&env_pyproject_toml_path).unwrap_or_default()
+                    != original_pyproject_toml
+                    || ex::fs::read_to_string(&env_uv_lock_path).unwrap_or_default()
+                        != original_uv_lock
+                {
+                    changed = true;
+                }
+            }
+            definitions.insert("python_envs".to_string(), python_envs.into());
+
             let new_pyproject_toml =
                 ex::fs::read_to_string(pyproject_toml_path).unwrap_or_else(|_| String::new());
             let new_poetry_lock =
@@ -1359,12 +2352,18 @@ fn add_python(
                 changed = true;
             }
 
-            overlays.push(
-                "(final: prev: {
-                   uv = uv2nix.packages.\"${system}\".uv-bin;
-            })"
-                .to_string(),
-            );
+            overlays.push(Expr::overlay(
+                AttrSet::new()
+                    .attr(
+                        "uv",
+                        Expr::var("uv2nix").select([
+                            Key::from("packages"),
+                            Key::Dynamic(Expr::var("system")),
+                            Key::from("uv-bin"),
+                        ]),
+                    )
+                    .into(),
+            ));
         }
         None => {
             if uv_lock_path.exists() {
@@ -1377,6 +2376,280 @@ fn add_python(
     Ok(changed)
 }
 
+/// 'python3.12' -> 'python312', the nixpkgs attribute name
+fn nixpkgs_python_attribute(version: &str) -> Result<String> {
+    if !Regex::new(r"^\d+\.\d+$").unwrap().is_match(version) {
+        bail!(format!(
+            "Python version must be x.y (not x.y.z, z is given by nixpkgs version). Was '{}'",
+            version
+        ));
+    }
+    Ok(format!("python{}", version.replace('.', "")))
+}
+
+/// The shared parts needed to lock a python environment with ancient-poetry
+struct PythonLocker<'a> {
+    ancient_poetry: &'a vcs::TofuVCS,
+    nixpkgs: &'a config::TofuNixPkgs,
+    uv_flake: &'a vcs::TofuVCS,
+    flake_dir: &'a Path,
+    in_non_spec_but_cached_values: &'a HashMap<String, String>,
+}
+
+impl PythonLocker<'_> {
+    /// Lock one python environment (pyproject.toml -> uv.lock),
+    /// and write the rewritten (nix store path) version to `rewritten_dir`.
+    ///
+    /// Returns whether setuptools needs the 'wheel' build system expansion
+    #[allow(clippy::too_many_arguments)]
+    fn lock(
+        &self,
+        python_version: &str,
+        ecosystem_date: jiff::civil::Date,
+        packages: &mut HashMap<SafePythonName, config::TofuPythonPackageDefinition>,
+        uv_lock_env: Option<&HashMap<String, String>>,
+        has_editable_packages: bool,
+        pyproject_toml_path: &Path,
+        uv_lock_path: &Path,
+        lock_dir: &Path,
+        rewritten_dir: &str,
+        out_non_spec_but_cached_values: &mut HashMap<String, String>,
+        git_tracked_files: &mut Vec<String>,
+    ) -> Result<bool> {
+        let prep_result = prep_packages_for_pyproject_toml(
+            packages,
+            self.in_non_spec_but_cached_values,
+            out_non_spec_but_cached_values,
+            pyproject_toml_path,
+        )?;
+        add_python_patches(self.flake_dir, packages, git_tracked_files)?;
+        let mut out_python_packages = prep_result.pyproject_fragment;
+        if has_editable_packages && !out_python_packages.contains_key("pip") {
+            out_python_packages.insert("pip".to_string(), toml::Value::String(">0".to_string()));
+        }
+
+        //out_python_packages.sort();
+        ancient_poetry(
+            self.ancient_poetry,
+            self.nixpkgs,
+            self.uv_flake,
+            packages,
+            &out_python_packages,
+            pyproject_toml_path,
+            uv_lock_path,
+            python_version,
+            &nixpkgs_python_attribute(python_version)?,
+            ecosystem_date,
+            uv_lock_env,
+        )?;
+
+        rewrite_poetry(
+            lock_dir,
+            &self.flake_dir.join(rewritten_dir),
+            &prep_result.writeable_to_nix_store_paths,
+        )?;
+        let extend_build_systems = check_if_setuptools_needs_expansion(uv_lock_path)?;
+        write_setup_cfg(
+            self.flake_dir,
+            rewritten_dir,
+            ecosystem_date,
+            git_tracked_files,
+        )?;
+        git_tracked_files.push(format!("{rewritten_dir}/uv.lock"));
+        git_tracked_files.push(format!("{rewritten_dir}/pyproject.toml"));
+        Ok(extend_build_systems)
+    }
+}
+
+/// Copy the python package patch files into the flake (so nix can see them),
+/// and add them to the package's patches
+fn add_python_patches(
+    flake_dir: &Path,
+    packages: &mut HashMap<SafePythonName, config::TofuPythonPackageDefinition>,
+    git_tracked_files: &mut Vec<String>,
+) -> Result<()> {
+    for (name, spec) in packages.iter_mut() {
+        if spec.patches.is_empty() {
+            continue;
+        }
+        let mut nix_paths = Vec::new();
+        for patch in &spec.patches {
+            let content = ex::fs::read(anysnake2::config_relative_path(patch))
+                .with_context(|| format!("Failed to read patch file {patch} for {name}"))?;
+            let filename = nix_path_filename(patch)?;
+            // content hash in the name, so different envs can patch the same package differently
+            let rel_path = format!(
+                "python_patches/{name}/{}-{filename}",
+                &sha256::digest(content.as_slice())[..16]
+            );
+            let target = flake_dir.join(&rel_path);
+            ex::fs::create_dir_all(target.parent().unwrap())?;
+            if ex::fs::read(&target).ok().as_deref() != Some(content.as_slice()) {
+                ex::fs::write(&target, &content)?;
+            }
+            git_tracked_files.push(rel_path.clone());
+            nix_paths.push(Expr::path(rel_path));
+        }
+        spec.anysnake_override_attrs
+            .get_or_insert_with(HashMap::new)
+            .insert(
+                "patches".to_string(),
+                Expr::var("old")
+                    .select_or(["patches"], Expr::list(Vec::<Expr>::new()))
+                    .bin_op("++", Expr::list(nix_paths))
+                    .to_string(),
+            );
+    }
+    Ok(())
+}
+
+/// The uv2nix definitions for one python environment.
+/// `suffix` is appended to every definition name, so that multiple environments can coexist.
+#[allow(clippy::too_many_lines)]
+fn add_python_env_definitions(
+    definitions: &mut BTreeMap<String, Expr>,
+    suffix: &str,
+    workspace: &str,
+    python_major_minor: &str,
+    source_preference: config::SourcePreference,
+    extend_build_systems: bool,
+    python_packages: &HashMap<SafePythonName, config::TofuPythonPackageDefinition>,
+) {
+    let (local_anysnake_overrides, local_user_overrides) = //todo: override_attrs...
+        format_overrides(python_packages);
+    let mk_pyproject_overlay = |preference: config::SourcePreference| {
+        Expr::var(format!("workspace{suffix}.mkPyprojectOverlay")).apply([AttrSet::new()
+            .attr("sourcePreference", Expr::str(preference.as_str()))
+            .into()])
+    };
+    let empty_overlay = || Expr::overlay(AttrSet::new().into());
+
+    definitions.insert(
+        format!("workspace{suffix}"),
+        Expr::var("uv2nix.lib.workspace.loadWorkspace").apply([AttrSet::new()
+            .attr("workspaceRoot", Expr::path(workspace))
+            .into()]),
+    );
+
+    definitions.insert(
+        format!("overlay{suffix}"),
+        mk_pyproject_overlay(source_preference),
+    );
+
+    // packages with a 'prefer' that differs from uv2nix.prefer_wheels
+    // are taken from an overlay with the other sourcePreference
+    // patches can only be applied to sdists
+    let effective_preference = |spec: &config::TofuPythonPackageDefinition| {
+        spec.prefer.or(if spec.patches.is_empty() {
+            None
+        } else {
+            Some(config::SourcePreference::Sdist)
+        })
+    };
+    let other_preference = python_packages
+        .iter()
+        .filter_map(|(name, spec)| {
+            effective_preference(spec)
+                .filter(|prefer| *prefer != source_preference)
+                .map(|prefer| (name.to_string(), prefer))
+        })
+        .collect::<BTreeMap<_, _>>();
+    definitions.insert(
+        format!("source_preference_overrides{suffix}"),
+        match other_preference.values().next() {
+            Some(other) => Expr::overlay(Expr::let_in(
+                vec![Binding::Attr(
+                    vec!["alternative".into()],
+                    mk_pyproject_overlay(*other).apply([Expr::var("final"), Expr::var("prev")]),
+                )],
+                other_preference
+                    .keys()
+                    .map(|name| {
+                        (
+                            name.as_str(),
+                            Expr::var("alternative").select([name.as_str()]),
+                        )
+                    })
+                    .collect::<AttrSet>()
+                    .into(),
+            )),
+            None => empty_overlay(),
+        },
+    );
+
+    definitions.insert(
+        format!("fix_resolve_build_systems{suffix}"),
+        if extend_build_systems {
+            // only if setuptools is in arg
+            Expr::overlay(
+                AttrSet::new()
+                    .attr(
+                        "resolveBuildSystem",
+                        Expr::raw(
+                            "arg: prev.resolveBuildSystem (arg
+                            // (
+                              if arg.setuptools or null != null
+                              then {wheel = [];}
+                              else {}
+                            ))",
+                        ),
+                    )
+                    .into(),
+            )
+        } else {
+            empty_overlay()
+        },
+    );
+
+    definitions.insert(
+        format!("local_anysnake_overrides{suffix}"),
+        Expr::overlay(local_anysnake_overrides.into()),
+    );
+    definitions.insert(
+        format!("local_user_overrides{suffix}"),
+        Expr::overlay(local_user_overrides.into()),
+    );
+
+    definitions.insert(
+        format!("pyprojectOverrides{suffix}"),
+        Expr::list([
+            Expr::var(format!("fix_resolve_build_systems{suffix}")),
+            Expr::var("uv2nix_override_collection.overrides").apply([Expr::var("pkgs")]),
+            Expr::var(format!("local_anysnake_overrides{suffix}")),
+            Expr::var(format!("local_user_overrides{suffix}")),
+        ]),
+    ); //todo: insert override_attrs here.
+    definitions.insert(
+        format!("interpreter{suffix}"),
+        Expr::var("pkgs").select([python_major_minor]),
+    );
+    // Use base package set from pyproject.nix builders
+    definitions.insert(
+        format!("pythonSet{suffix}"),
+        Expr::var("pkgs.callPackage")
+            .apply([
+                Expr::var("pyproject-nix.build.packages"),
+                AttrSet::new()
+                    .attr("python", Expr::var(format!("interpreter{suffix}")))
+                    .into(),
+            ])
+            .select(["overrideScope"])
+            .apply([
+                Expr::var("pkgs.lib.composeManyExtensions").apply([Expr::list([
+                    Expr::var("pyproject-build-systems.overlays.default"),
+                    Expr::var(format!("overlay{suffix}")),
+                    Expr::var(format!("source_preference_overrides{suffix}")),
+                ])
+                .bin_op("++", Expr::var(format!("pyprojectOverrides{suffix}")))]),
+            ]),
+    );
+    //Override host packages with build fixups
+    /* definitions.insert(
+        "pythonSet".to_string(),
+        "pythonSet'.pythonPkgsHostHost.overrideScope pyprojectOverrides".to_string(),
+    ); */
+}
+
 pub struct PrefetchResult {
     pub path: String,
     pub sha256: String,
@@ -1420,6 +2693,65 @@ pub fn prefetch_hg_store_path(url: &str, rev: &str) -> Result<PrefetchResult> {
     Ok(PrefetchResult { path, sha256 })
 }
 
+/// download and unpack a tarball/zip. `hash` may be empty if it's not known yet
+pub fn prefetch_tarball_store_path(url: &str, hash: &str) -> Result<PrefetchResult> {
+    let mut proc = Command::new("nix");
+    proc.args([
+        "store",
+        "prefetch-file",
+        "--unpack",
+        "--json",
+        "--name",
+        "source",
+    ]);
+    if !hash.is_empty() {
+        proc.args(["--expected-hash", hash]);
+    }
+    proc.arg(url);
+    debug!("running {proc:?}");
+    let proc_res = proc.output().context("failed on nix store prefetch-file")?;
+    if !proc_res.status.success() {
+        bail!(
+            "nix store prefetch-file {url} failed: {}",
+            String::from_utf8_lossy(&proc_res.stderr)
+        );
+    }
+    let prefetched: serde_json::Value = serde_json::from_slice(&proc_res.stdout)
+        .context("nix store prefetch-file output was not json")?;
+    Ok(PrefetchResult {
+        path: prefetched["storePath"]
+            .as_str()
+            .context("No storePath in nix store prefetch-file output")?
+            .to_string(),
+        sha256: prefetched["hash"]
+            .as_str()
+            .context("No hash in nix store prefetch-file output")?
+            .to_string(),
+    })
+}
+
+/// download and unpack an archive into the nix store - what pkgs.fetchzip would produce
+pub fn prefetch_url_store_path(url: &str, _rev: &str) -> Result<PrefetchResult> {
+    let mut proc = Command::new("nix-prefetch-url");
+    proc.args(["--unpack", "--print-path", "--name", "source", url]);
+    debug!("running {proc:?}");
+    let proc_res = proc.output().context("failed on nix-prefetch-url")?;
+    if !proc_res.status.success() {
+        bail!("nix-prefetch-url failed with code {}", proc_res.status);
+    }
+    let stdout = std::str::from_utf8(&proc_res.stdout)?.trim();
+    let mut lines = stdout.lines();
+    let hash = lines
+        .next()
+        .with_context(|| format!("No hash in nix-prefetch-url output. Output was {stdout}"))?;
+    let path = lines
+        .next()
+        .with_context(|| format!("No path in nix-prefetch-url output. Output was {stdout}"))?
+        .to_string();
+    let sha256 = crate::tofu::convert_hash_to_subresource_format(hash)?;
+    Ok(PrefetchResult { path, sha256 })
+}
+
 pub fn prefetch_git_store_path(url: &str, rev: &str) -> Result<PrefetchResult> {
     let nix_prefetch_git_url = format!(
         "{}#nix-prefetch-git",
@@ -1549,14 +2881,15 @@ pub fn prefetch_github_store_path(url: &str, rev: &str) -> Result<PrefetchResult
 /// rewrite all /nix/store references in poetry.toml and lock into ../, and place in new folder
 /// we need it to find them in the nix store, but for the locking we needed them outside.
 fn rewrite_poetry(
-    flake_dir: &Path,
+    input_dir: &Path,
+    output_dir: &Path,
     writeable_to_nix_store_paths: &HashMap<String, String>,
 ) -> Result<()> {
-    ex::fs::create_dir_all(flake_dir.join("uv_rewritten"))?;
+    ex::fs::create_dir_all(output_dir)?;
 
     let filename = "pyproject.toml";
-    let input_filename = flake_dir.join("uv").join(filename);
-    let output_filename = flake_dir.join("uv_rewritten").join(filename);
+    let input_filename = input_dir.join(filename);
+    let output_filename = output_dir.join(filename);
     let raw = ex::fs::read_to_string(input_filename).context("rewrite_poetry")?;
     let mut out = raw;
     for (search, replace) in writeable_to_nix_store_paths {
@@ -1568,8 +2901,8 @@ fn rewrite_poetry(
     //dbg!(&str_flake_dir);
 
     let filename = "uv.lock";
-    let input_filename = flake_dir.join("uv").join(filename);
-    let output_filename = flake_dir.join("uv_rewritten").join(filename);
+    let input_filename = input_dir.join(filename);
+    let output_filename = output_dir.join(filename);
     let raw = ex::fs::read_to_string(input_filename).context("Rewrite_poetry")?;
     let mut out = raw;
     for (search, replace) in writeable_to_nix_store_paths {
@@ -1591,16 +2924,17 @@ fn rewrite_poetry(
 /// this places a dummy setup.cfg in our generated top level project
 fn write_setup_cfg(
     flake_dir: &Path,
+    rewritten_dir: &str,
     ecosystem_date: jiff::civil::Date,
     git_tracked_files: &mut Vec<String>,
 ) -> Result<()> {
     if ecosystem_date <= jiff::civil::Date::constant(2022, 3, 24) {
-        let setup_cfg = flake_dir.join("uv_rewritten/setup.cfg");
+        let setup_cfg = flake_dir.join(rewritten_dir).join("setup.cfg");
         ex::fs::write(
             setup_cfg,
             "[metadata]\nname = anysnake2-to-ancient-poetry-uv\nversion = 0.1.0\n",
         )?;
-        git_tracked_files.push("uv_rewritten/setup.cfg".to_string());
+        git_tracked_files.push(format!("{rewritten_dir}/setup.cfg"));
     }
     Ok(())
 }
@@ -1647,3 +2981,127 @@ fn check_if_setuptools_needs_expansion(uv_lock_path: &Path) -> Result<bool> {
 
     Ok(false)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// compare with `src/snapshots/<name>.nix`, `ANYSNAKE2_UPDATE_SNAPSHOTS=1` rewrites them
+    fn assert_snapshot(name: &str, actual: &str) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/snapshots")
+            .join(format!("{name}.nix"));
+        if std::env::var("ANYSNAKE2_UPDATE_SNAPSHOTS").is_ok() {
+            std::fs::write(&path, actual).unwrap();
+        }
+        let expected = std::fs::read_to_string(&path).unwrap_or_default();
+        assert_eq!(expected, actual, "snapshot {name} differs");
+    }
+
+    #[test]
+    fn test_flake_snapshot() {
+        let nixpkgs_url = vcs::TofuVCS::GitHub {
+            owner: "NixOS".to_string(),
+            repo: "nixpkgs".to_string(),
+            branch: "master".to_string(),
+            rev: "24.11".to_string(),
+        };
+        let inputs = vec![
+            InputFlake::new(
+                "flake-utils",
+                &vcs::TofuVCS::GitHub {
+                    owner: "numtide".to_string(),
+                    repo: "flake-utils".to_string(),
+                    branch: "main".to_string(),
+                    rev: "11707dc2f618dd54ca8739b309ec4fc024de578b".to_string(),
+                },
+                None,
+                &[],
+            ),
+            InputFlake::new("nixpkgs", &nixpkgs_url, None, &[]),
+            InputFlake::new(
+                "hello",
+                &vcs::TofuVCS::Git {
+                    url: "https://example.com/hello".to_string(),
+                    branch: "main".to_string(),
+                    rev: "0123456789abcdef".to_string(),
+                },
+                Some("sub dir".to_string()),
+                &["nixpkgs"],
+            ),
+        ];
+        let mut definitions = BTreeMap::new();
+        definitions.insert("R_tracked".to_string(), Expr::Null);
+        definitions.insert("python_envs".to_string(), AttrSet::new().into());
+        definitions.insert(
+            "overlays".to_string(),
+            Expr::list([Expr::overlay(
+                AttrSet::new()
+                    .attr("pkgconfig", Expr::var("final.pkg-config"))
+                    .into(),
+            )]),
+        );
+        let nixpkgs_pkgs: BTreeSet<String> = ["cacert", "hello.packages.${system}.default"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let nixpkgs = config::TofuNixPkgs {
+            url: nixpkgs_url,
+            packages: Vec::new(),
+            allow_unfree: true,
+            permitted_insecure_packages: Some(vec!["openssl-1.1.1w".to_string()]),
+            overlay: None,
+            config: toml::from_str("cudaSupport = false").unwrap(),
+            overlays: Vec::new(),
+        };
+        let dev_shell = config::TofuDevShell {
+            inputs: vec!["git".to_string()],
+            shell: "bash".to_string(),
+        };
+        assert_snapshot(
+            "minimal_flake",
+            &flake_expr(&inputs, definitions, &nixpkgs_pkgs, &nixpkgs, &dev_shell)
+                .unwrap()
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn test_python_env_definitions_snapshot() {
+        let mut packages = HashMap::new();
+        packages.insert(
+            SafePythonName::new("scanpy"),
+            config::TofuPythonPackageDefinition {
+                source: config::TofuPythonPackageSource::VersionConstraint(">=1.10".to_string()),
+                editable_path: None,
+                override_attrs: [("postPatch".to_string(), "''\n  echo hello\n''".to_string())]
+                    .into_iter()
+                    .collect(),
+                anysnake_override_attrs: None,
+                patch_before_lock: None,
+                build_systems: Some(vec!["setuptools".to_string()]),
+                ecosystem_date: None,
+                prefer: Some(config::SourcePreference::Sdist),
+                patches: Vec::new(),
+            },
+        );
+        let mut definitions = BTreeMap::new();
+        add_python_env_definitions(
+            &mut definitions,
+            "_old",
+            "uv_rewritten/old",
+            "python311",
+            config::SourcePreference::Wheel,
+            true,
+            &packages,
+        );
+        let bindings = definitions
+            .into_iter()
+            .map(|(name, value)| Binding::Attr(vec![name.into()], value))
+            .collect();
+        assert_snapshot(
+            "python_env_definitions",
+            &Expr::let_in(bindings, Expr::var("pythonSet_old")).to_string(),
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use toml_edit::value;
+
+use crate::config;
+use crate::pypi::newest_version_before;
+use crate::r_parsing::{
+    parse_description_for_import, parse_renv_lock, version_satisfies, ImportedR, NixRDate,
+};
+use crate::tofu::{fetch_nixr_dates, tofu_nixr_url};
+use anysnake2::util::{change_toml_file, get_proxy_req, TomlUpdates};
+
+pub fn handle_import_command(
+    config_file: &Path,
+    flake_dir: &Path,
+    parsed_config: &config::TofuConfigToml,
+    matches: &ArgMatches,
+) -> Result<()> {
+    match matches.subcommand() {
+        Some(("r", sc)) => {
+            let file = sc
+                .get_one::<String>("file")
+                .context("No file passed to 'import r'")?;
+            import_r(
+                config_file,
+                flake_dir,
+                parsed_config.r.as_ref(),
+                Path::new(file),
+            )
+        }
+        _ => {
+            bail!("Please pass an ecosystem, as in 'anysnake2 import r renv.lock'");
+        }
+    }
+}
+
+/// The newest nixR date whose R fulfills the constraint.
+/// For renv's exact R versions, we fall back to the same major.minor
+pub fn pick_nixr_date<'a>(
+    dates: &'a [NixRDate],
+    r_constraint: Option<&(String, String)>,
+) -> Option<&'a NixRDate> {
+    let Some(r_constraint) = r_constraint else {
+        return dates.last();
+    };
+    let satisfying = dates.iter().rev().find(|date| {
+        date.r_version
+            .as_ref()
+            .is_some_and(|r_version| version_satisfies(r_version, r_constraint))
+    });
+    if satisfying.is_some() || r_constraint.0 != "==" {
+        return satisfying;
+    }
+    let major_minor = r_constraint
+        .1
+        .split('.')
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(".");
+    dates.iter().rev().find(|date| {
+        date.r_version
+            .as_ref()
+            .is_some_and(|r_version| r_version.starts_with(&format!("{major_minor}.")))
+    })
+}
+
+/// version -> first release date on CRAN, via crandb
+fn cran_release_dates(name: &str) -> Result<BTreeMap<String, String>> {
+    let url = format!("https://crandb.r-pkg.org/{name}/all");
+    debug!("querying {url}");
+    let json = get_proxy_req()?
+        .get(&url)
+        .call()
+        .with_context(|| format!("Failed to query {url}"))?
+        .body_mut()
+        .read_to_string()?;
+    let parsed: serde_json::Value = serde_json::from_str(&json)?;
+    Ok(parsed["timeline"]
+        .as_object()
+        .with_context(|| format!("No timeline in {url}"))?
+        .iter()
+        .filter_map(|(version, date)| {
+            date.as_str()
+                .map(|date| (version.clone(), date.chars().take(10).collect()))
+        })
+        .collect())
+}
+
+#[allow(clippy::too_many_lines)]
+fn import_r(
+    config_file: &Path,
+    flake_dir: &Path,
+    r: Option<&config::TofuR>,
+    input: &Path,
+) -> Result<()> {
+    let raw = ex::fs::read_to_string(input)?;
+    let imported: ImportedR = if raw.trim_start().starts_with('{') {
+        parse_renv_lock(&raw).with_context(|| format!("Failed to parse {}", input.display()))?
+    } else {
+        parse_description_for_import(&raw)
+    };
+
+    let mut updates: TomlUpdates = Vec::new();
+    let nixr_url = tofu_nixr_url(r, &mut updates)?;
+    let dates = fetch_nixr_dates(&nixr_url, flake_dir)?;
+    let Some(date) = pick_nixr_date(&dates, imported.r_constraint.as_ref()) else {
+        bail!(
+            "No nixR date provides an R matching {:?}. See 'anysnake2 r dates'",
+            imported.r_constraint
+        );
+    };
+    info!(
+        "Using R date {} (R {})",
+        date.date,
+        date.r_version.as_deref().unwrap_or("unknown")
+    );
+
+    let mut packages: Vec<String> = r.map(|r| r.packages.clone()).unwrap_or_default();
+    let mut differing = Vec::new();
+    for pkg in &imported.packages {
+        if let Some(github_url) = &pkg.github_url {
+            if !r.is_some_and(|r| r.vcs_packages.contains_key(&pkg.name)) {
+                let mut entry = toml_edit::Table::new().into_inline_table();
+                entry.insert("url", github_url.into());
+                updates.push((
+                    vec![
+                        "R".to_string(),
+                        "vcs_packages".to_string(),
+                        pkg.name.clone(),
+                    ],
+                    value(entry),
+                ));
+            }
+            continue;
+        }
+        packages.push(pkg.name.clone());
+        if let Some(constraint) = &pkg.constraint {
+            let at_date = match cran_release_dates(&pkg.name) {
+                Ok(release_dates) => newest_version_before(&release_dates, &date.date),
+                Err(e) => {
+                    warn!(
+                        "Could not check version of {} (not on CRAN?): {e}",
+                        pkg.name
+                    );
+                    continue;
+                }
+            };
+            let at_date = at_date.unwrap_or_else(|| "none".to_string());
+            if !version_satisfies(&at_date, constraint) {
+                differing.push((pkg.name.clone(), constraint.clone(), at_date));
+            }
+        }
+    }
+    packages.sort();
+    packages.dedup();
+    let mut packages_array = toml_edit::Array::new();
+    for pkg in &packages {
+        packages_array.push(pkg.as_str());
+    }
+    updates.push((
+        vec!["R".to_string(), "date".to_string()],
+        value(date.date.clone()),
+    ));
+    updates.push((
+        vec!["R".to_string(), "packages".to_string()],
+        value(packages_array),
+    ));
+    change_toml_file(&PathBuf::from(config_file), updates)?;
+    println!(
+        "Imported {} R packages into {}",
+        imported.packages.len(),
+        config_file.display()
+    );
+
+    if !differing.is_empty() {
+        println!();
+        println!(
+            "These packages differ from what CRAN had on {}. Candidates for [R.additional_packages]:",
+            date.date
+        );
+        for (name, (op, version), at_date) in &differing {
+            println!(
+                "\t{name}: wanted {op} {version}, {} has {at_date}",
+                date.date
+            );
+        }
+        let exact: Vec<_> = differing
+            .iter()
+            .filter(|(_, (op, _), _)| op == "==")
+            .collect();
+        if !exact.is_empty() {
+            println!();
+            println!("[R.additional_packages]");
+            for (name, (_, version), _) in exact {
+                println!(
+                    "\"{name}_{version}\" = '''{{
+  src = pkgs.fetchurl {{
+    url = \"https://cran.r-project.org/src/contrib/Archive/{name}/{name}_{version}.tar.gz\";
+    sha256 = \"\"; # fill in from the build error
+  }};
+}}'''"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pick_nixr_date() {
+        let dates = vec![
+            NixRDate {
+                date: "2023-06-01".to_string(),
+                r_version: Some("4.3.0".to_string()),
+                bioconductor_version: Some("3.17".to_string()),
+            },
+            NixRDate {
+                date: "2023-11-01".to_string(),
+                r_version: Some("4.3.2".to_string()),
+                bioconductor_version: Some("3.18".to_string()),
+            },
+            NixRDate {
+                date: "2024-05-10".to_string(),
+                r_version: Some("4.4.0".to_string()),
+                bioconductor_version: Some("3.19".to_string()),
+            },
+        ];
+        let pick = |op: &str, version: &str| {
+            pick_nixr_date(&dates, Some(&(op.to_string(), version.to_string())))
+                .map(|x| x.date.as_str())
+        };
+        assert_eq!(pick("==", "4.3.0"), Some("2023-06-01"));
+        assert_eq!(pick("==", "4.3.1"), Some("2023-11-01"));
+        assert_eq!(pick(">=", "4.1.0"), Some("2024-05-10"));
+        assert_eq!(pick("==", "3.6.3"), None);
+        assert_eq!(
+            pick_nixr_date(&dates, None).map(|x| x.date.as_str()),
+            Some("2024-05-10")
+        );
+    }
+}
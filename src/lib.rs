@@ -2,6 +2,7 @@ pub mod util;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+use std::path::{Path, PathBuf};
 use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc, OnceLock};
 
 use anyhow::Result;
@@ -17,6 +18,11 @@ lazy_static! {
     static ref OUTSIDE_NIXPKGS_URL: OnceLock<String> = OnceLock::new();
 }
 
+lazy_static! {
+    ///Set after reading the config - relative paths in it are relative to its directory
+    static ref CONFIG_DIR: OnceLock<PathBuf> = OnceLock::new();
+}
+
 pub fn install_ctrl_c_handler() -> Result<()> {
     let c = CTRL_C_ALLOWED.clone();
     Ok(ctrlc::set_handler(move || {
@@ -37,6 +43,19 @@ pub fn get_outside_nixpkgs_url() -> Option<&'static str> {
     OUTSIDE_NIXPKGS_URL.get().map(|s| s.as_str())
 }
 
+pub fn define_config_dir(dir: PathBuf) {
+    CONFIG_DIR.set(dir).expect("Trying to set config dir twice");
+}
+
+/// Resolve a path from anysnake2.toml against the directory it's in (not the current directory)
+pub fn config_relative_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    match CONFIG_DIR.get() {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
 pub fn run_without_ctrl_c<T>(func: impl Fn() -> Result<T>) -> Result<T> {
     CTRL_C_ALLOWED.store(false, Ordering::SeqCst);
     let res = func();
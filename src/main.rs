@@ -27,9 +27,14 @@ use tofu::apply_trust_on_first_use;
 
 mod config;
 mod flake_writer;
+mod import;
+mod nix_expr;
+mod pypi;
 mod python_parsing;
+mod r_parsing;
 mod tofu;
 mod vcs;
+mod why;
 
 /* TODO
 
@@ -70,6 +75,7 @@ fn main() {
     }
 }
 
+#[allow(clippy::too_many_lines)]
 fn parse_args() -> ArgMatches {
     clap::Command::new("Anysnake2")
         .version(VERSION)
@@ -97,6 +103,13 @@ fn parse_args() -> ArgMatches {
                 //.default_value("2")
                 .help("Sets the level of verbosity (0=quiet,1=error/warnings, 2=info (default), 3=debug, 4=trace, 5=trace)"),
         )
+        .arg(
+            Arg::new("refresh-editable")
+                .long("refresh-editable")
+                .value_name("PACKAGE")
+                .help("re-download/re-clone an editable package (or clone) whose source changed, moving the old checkout aside")
+                .action(clap::ArgAction::Append)
+        )
         .arg(
             Arg::new("_running_version")
                 .long("_running_version")
@@ -127,7 +140,47 @@ fn parse_args() -> ArgMatches {
         .subcommand(clap::Command::new("develop").about("run nix develop, and go back to this dir with your favourite shell"))
         .subcommand(clap::Command::new("version").about("the version actually used by the config file. Error if no config file is present (use --version for the version of this binary"))
         .subcommand(clap::Command::new("attach").about("attach to previously running session"))
+        .subcommand(
+            clap::Command::new("venv")
+                .about("manage the venv of editable python packages")
+                .subcommand(
+                    clap::Command::new("rebuild")
+                        .about("reinstall editable python packages into the venv (all, or just one)")
+                        .arg(Arg::new("package").required(false)),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("why")
+                .about("explain why a package is at it's locked version")
+                .subcommand(
+                    clap::Command::new("python")
+                        .about("show the dependency chain and constraints from uv.lock for a python package")
+                        .arg(Arg::new("package").required(true))
+                        .arg(
+                            Arg::new("env")
+                                .long("env")
+                                .help("query a [python.envs.<name>] lock instead of the default environment ('python' for [python])"),
+                        ),
+                ),
+        )
 
+        .subcommand(
+            clap::Command::new("r")
+                .about("information about the R ecosystem")
+                .subcommand(
+                    clap::Command::new("dates")
+                        .about("list the dates available in nixR with their R and Bioconductor versions"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("import")
+                .about("import dependencies from other tools into anysnake2.toml")
+                .subcommand(
+                    clap::Command::new("r")
+                        .about("fill [R] packages and date from a renv.lock or DESCRIPTION file")
+                        .arg(Arg::new("file").required(true)),
+                ),
+        )
         .subcommand(
             clap::Command::new("upgrade")
             .arg(
@@ -179,6 +232,38 @@ fn handle_config_command(matches: &ArgMatches) -> Result<bool> {
     }
 }
 
+fn handle_r_command(
+    flake_dir: &Path,
+    parsed_config: &config::TofuConfigToml,
+    matches: &ArgMatches,
+) -> Result<()> {
+    match matches.subcommand() {
+        Some(("dates", _)) => {
+            let mut updates = Vec::new(); // not written - we just want to know the newest nixR
+            let url = tofu::tofu_nixr_url(parsed_config.r.as_ref(), &mut updates)?;
+            let current = parsed_config.r.as_ref().map(|r| r.date.as_str());
+            println!("date\tR\tBioconductor");
+            for date in tofu::fetch_nixr_dates(&url, flake_dir)? {
+                println!(
+                    "{}\t{}\t{}{}",
+                    date.date,
+                    date.r_version.as_deref().unwrap_or("?"),
+                    date.bioconductor_version.as_deref().unwrap_or("?"),
+                    if current == Some(date.date.as_str()) {
+                        "\t(current)"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            Ok(())
+        }
+        _ => {
+            bail!("Please pass a subcommand, as in 'anysnake2 r dates'");
+        }
+    }
+}
+
 fn configure_logging(matches: &ArgMatches) -> Result<()> {
     let default_verbosity = 2;
     let str_verbosity = matches.get_one::<String>("verbose");
@@ -227,8 +312,11 @@ fn switch_to_configured_version(
                         repo: _,
                         branch: _,
                         rev,
-                    } => rev,
-                    vcs::TofuVCS::Mercurial { .. } => {
+                    }
+                    | vcs::TofuVCS::Forge { rev, .. } => rev,
+                    vcs::TofuVCS::Mercurial { .. }
+                    | vcs::TofuVCS::Path { .. }
+                    | vcs::TofuVCS::Tarball { .. } => {
                         bail!("Anysnake itself must be hosted on a git repo")
                     }
                 };
@@ -313,6 +401,13 @@ fn inner_main() -> Result<()> {
     switch_to_configured_version(&minimal_parsed_config, &matches)?;
 
     let parsed_config: config::ConfigToml = config::ConfigToml::from_file(&config_file)?;
+    if let Some(config_dir) = parsed_config
+        .anysnake2_toml_path
+        .as_ref()
+        .and_then(|path| path.parent())
+    {
+        anysnake2::define_config_dir(config_dir.to_path_buf());
+    }
     if cmd == "version" {
         //output the version you'd actually be using!
         print_version_and_exit();
@@ -321,15 +416,36 @@ fn inner_main() -> Result<()> {
     let in_non_spec_but_cached_values = load_cached_values(&flake_dir)?;
     let mut out_non_spec_but_cached_values: HashMap<String, String> = HashMap::new();
 
-    let tofued_config = apply_trust_on_first_use(parsed_config)?;
+    let tofued_config = apply_trust_on_first_use(parsed_config, &flake_dir)?;
 
     if cmd == "attach" {
         return attach_to_previous_container(&flake_dir);
     }
 
+    if let Some(("why", sc)) = matches.subcommand() {
+        return why::handle_why_command(&flake_dir, &tofued_config, sc);
+    }
+
+    if let Some(("r", sc)) = matches.subcommand() {
+        return handle_r_command(&flake_dir, &tofued_config, sc);
+    }
+
+    if let Some(("import", sc)) = matches.subcommand() {
+        return import::handle_import_command(
+            Path::new(&config_file),
+            &flake_dir,
+            &tofued_config,
+            sc,
+        );
+    }
+
     let use_generated_file_instead = tofued_config.anysnake2.do_not_modify_flake;
 
-    if !(tofued_config.cmd.contains_key(cmd) || cmd == "build" || cmd == "run" || cmd == "develop")
+    if !(tofued_config.cmd.contains_key(cmd)
+        || cmd == "build"
+        || cmd == "run"
+        || cmd == "develop"
+        || cmd == "venv")
     {
         bail!(
             "Cmd {} not found.
@@ -343,6 +459,12 @@ fn inner_main() -> Result<()> {
 
     let mut tofued_config = tofued_config;
 
+    let refresh_editable: Vec<String> = matches
+        .get_many::<String>("refresh-editable")
+        .map(|x| x.cloned().collect())
+        .unwrap_or_default();
+    perform_clones(&flake_dir, &tofued_config, &refresh_editable, false)?;
+
     let flake_changed = flake_writer::write_flake(
         &flake_dir,
         &mut tofued_config,
@@ -362,7 +484,7 @@ fn inner_main() -> Result<()> {
                                                                                 // out again if we
                                                                                 // don't have to
 
-    perform_clones(&flake_dir, &tofued_config)?;
+    perform_clones(&flake_dir, &tofued_config, &refresh_editable, true)?;
 
     if let Some(("build", sc)) = matches.subcommand() {
         {
@@ -420,6 +542,7 @@ fn inner_main() -> Result<()> {
                                                                          //early error exit if you try to run an non-existant command
         if flake_changed.flake_nix_changed
             || flake_changed.python_lock_changed
+            || flake_changed.tracked_files_changed
             || !build_output.exists()
             || build_unfinished_file.exists()
         {
@@ -433,8 +556,26 @@ fn inner_main() -> Result<()> {
         }
 
         if let Some(python) = &tofued_config.python {
-            //todo
-            fill_venv(&python.version, &python.packages, &flake_dir)?;
+            let force_rebuild = match matches.subcommand() {
+                Some(("venv", sc)) => match sc.subcommand() {
+                    Some(("rebuild", rebuild_sc)) => {
+                        match rebuild_sc.get_one::<String>("package") {
+                            Some(pkg) => VenvRebuild::Some(vec![SafePythonName::new(
+                                safe_python_package_name(pkg),
+                            )]),
+                            None => VenvRebuild::All,
+                        }
+                    }
+                    _ => bail!("Please pass a venv subcommand, as in 'anysnake2 venv rebuild'"),
+                },
+                _ => VenvRebuild::Some(Vec::new()),
+            };
+            fill_venv(
+                &python.version,
+                &python.packages,
+                &flake_dir,
+                &force_rebuild,
+            )?;
             /* if let Some(r) = &tofued_config.r {
                 add_r_library_path(
                     &flake_dir,
@@ -448,6 +589,12 @@ fn inner_main() -> Result<()> {
         if out_non_spec_but_cached_values != in_non_spec_but_cached_values {
             save_cached_values(&flake_dir, &out_non_spec_but_cached_values)?;
         }
+        if cmd == "venv" {
+            if tofued_config.python.is_none() {
+                bail!("No [python] section in anysnake2.toml - no venv to rebuild");
+            }
+            return Ok(());
+        }
         if cmd == "develop" {
             if let Some(python) = &tofued_config.python {
                 write_develop_python_path(&flake_dir, &python.packages, &python.version)?;
@@ -585,6 +732,46 @@ fn inner_main() -> Result<()> {
                 }
                 paths.push("/anysnake2/venv/bin");
             };
+            if let Some(r) = tofued_config
+                .r
+                .as_ref()
+                .filter(|r| r.has_editable_packages())
+            {
+                let mut load_all = Vec::new();
+                for (pkg, spec) in &r.vcs_packages {
+                    if let Some(editable_path) = &spec.editable_path {
+                        let target_dir = fs::canonicalize(PathBuf::from(editable_path).join(pkg))
+                            .with_context(|| {
+                            format!("editable R package {pkg} was not present in file system (missing clone?)")
+                        })?;
+                        // rw, devtools::load_all compiles into the checkout
+                        binds.push((
+                            target_dir.to_string_lossy(),
+                            format!("/anysnake2/R_editable/{pkg}"),
+                            "rw".to_string(),
+                        ));
+                        load_all.push(format!(
+                            "devtools::load_all(\"/anysnake2/R_editable/{pkg}\", quiet = TRUE)"
+                        ));
+                    }
+                }
+                let rprofile = run_dir.join("Rprofile");
+                fs::write(
+                    &rprofile,
+                    format!(
+                        "if (file.exists(\"~/.Rprofile\")) source(\"~/.Rprofile\")\n\
+                        suppressMessages({{\n{}\n}})\n",
+                        load_all.join("\n")
+                    ),
+                )?;
+                binds.push((
+                    rprofile.to_string_lossy(),
+                    "/anysnake2/Rprofile".to_string(),
+                    "ro".to_string(),
+                ));
+                envs.push("R_LIBS_USER=/anysnake2/R_editable".to_string());
+                envs.push("R_PROFILE_USER=/anysnake2/Rprofile".to_string());
+            }
 
             if let Some(volumes_ro) = &tofued_config.container.volumes_ro {
                 for (from, to) in volumes_ro {
@@ -799,38 +986,87 @@ fn extract_python_package_version_from_uv_lock(
     bail!("Could not find package {} in uv.lock", safe_name);
 }
 
-fn download_and_unzip(url: &str, target_dir: &Path) -> Result<()> {
+/// Download a .tar.gz (e.g. a python sdist) and unpack it into `target_dir`,
+/// stripping the top level directory.
+/// If `expected_sha256` is given, the download is verified against it before unpacking.
+fn download_and_unpack(url: &str, expected_sha256: Option<&str>, target_dir: &Path) -> Result<()> {
     //remove target dir if it exists
     if target_dir.exists() {
         ex::fs::remove_dir_all(target_dir).context("Failed to remove target dir")?;
     }
     ex::fs::create_dir_all(target_dir).context("Failed to create target dir")?;
-    let download_filename = target_dir.join("download.tar.gz");
 
+    let mut downloaded = Vec::new();
     {
-        let tf = ex::fs::File::create(&download_filename)?;
-        let mut btf = std::io::BufWriter::new(tf);
         let mut req = anysnake2::util::get_proxy_req()?.get(url).call()?;
-        std::io::copy(&mut req.body_mut().as_reader(), &mut btf)?;
+        std::io::copy(&mut req.body_mut().as_reader(), &mut downloaded)
+            .with_context(|| format!("Failed to download {url}"))?;
     }
-    //call tar to unpack
-    Command::new("tar")
-        .args(["-xzf", "download.tar.gz", "--strip-components=1"])
-        .current_dir(target_dir)
-        .status()
-        .context("Failed to untar downloaded archive")?;
+    if let Some(expected_sha256) = expected_sha256 {
+        let actual_sha256 = sha256::digest(downloaded.as_slice());
+        if !actual_sha256.eq_ignore_ascii_case(expected_sha256) {
+            bail!("sha256 mismatch for {url}. Expected {expected_sha256}, got {actual_sha256}");
+        }
+    }
+    unpack_tar_gz(downloaded.as_slice(), target_dir)
+        .with_context(|| format!("Failed to unpack downloaded archive from {url}"))?;
 
-    ex::fs::remove_file(download_filename).context("Failed to remove download file")?;
+    Ok(())
+}
 
+/// unpack a .tar.gz, like tar -xzf --strip-components=1
+fn unpack_tar_gz(input: impl std::io::Read, target_dir: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(input));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let stripped: PathBuf = path.components().skip(1).collect();
+        if stripped.as_os_str().is_empty() {
+            continue;
+        }
+        if !stripped
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            bail!("Refusing to unpack archive entry outside of target: {path:?}");
+        }
+        let target = target_dir.join(stripped);
+        if let Some(parent) = target.parent() {
+            ex::fs::create_dir_all(parent)?;
+        }
+        entry
+            .unpack(&target)
+            .with_context(|| format!("Failed to unpack {}", path.display()))?;
+    }
     Ok(())
 }
 
+/// What we record in `.clone_info.json` for a clone.
+///
+/// For python packages coming from `PyPI`, that's the version from `uv.lock`,
+/// so we notice when the lock moves on.
+fn clone_source_id(
+    flake_dir: &Path,
+    name: &str,
+    source: &config::TofuPythonPackageSource,
+) -> Result<String> {
+    match source {
+        config::TofuPythonPackageSource::PyPi { .. }
+        | config::TofuPythonPackageSource::VersionConstraint(_) => {
+            let safe_name = safe_python_package_name(name);
+            let actual_version =
+                extract_python_package_version_from_uv_lock(flake_dir, &safe_name)?;
+            Ok(format!("pypi:{actual_version}"))
+        }
+        _ => Ok(format!("{}", source.without_username_in_url())),
+    }
+}
+
 fn clone(
     flake_dir: &Path,
     parent_dir: &str,
     name: &str,
     source: &config::TofuPythonPackageSource,
-    known_clones: &mut HashMap<String, String>,
     do_jujutsu: bool,
 ) -> Result<()> {
     let final_dir: PathBuf = [parent_dir, name].iter().collect();
@@ -845,25 +1081,30 @@ fn clone(
                     extract_python_package_version_from_uv_lock(flake_dir, &safe_name)?;
                 // I don't see how we get from what's in poetry.lock to the url right now, and this
                 // is at hand
-                let url =
+                let sdist =
                     anysnake2::util::get_pypi_package_source_url(&safe_name, Some(&actual_version))
                         .context("Failed to get python package source")?;
-                download_and_unzip(&url, &final_dir)?;
+                download_and_unpack(&sdist.url, Some(&sdist.sha256), &final_dir)?;
             }
             config::TofuPythonPackageSource::Url(url) => {
-                download_and_unzip(url, &final_dir)?;
+                download_and_unpack(url, None, &final_dir)?;
             }
             config::TofuPythonPackageSource::Vcs(tofu_vcs) => {
                 tofu_vcs.clone_repo(&final_dir.to_string_lossy(), do_jujutsu)?;
             }
         }
-
-        known_clones.insert(name.to_string(), source.to_string());
     }
     Ok(())
 }
 
-fn perform_clones(flake_dir: &Path, parsed_config: &config::TofuConfigToml) -> Result<()> {
+/// `include_editables`: the editable packages need the python lock (= a written flake),
+/// plain [clones] do not, and may contain files the flake needs (e.g. a rust toolchain file)
+fn perform_clones(
+    flake_dir: &Path,
+    parsed_config: &config::TofuConfigToml,
+    refresh: &[String],
+    include_editables: bool,
+) -> Result<()> {
     let do_jujustu = parsed_config.clone_options.jujutsu;
     // the old school 'clones' clones
     let mut todo: HashMap<String, HashMap<String, config::TofuPythonPackageSource>> =
@@ -880,7 +1121,7 @@ fn perform_clones(flake_dir: &Path, parsed_config: &config::TofuConfigToml) -> R
         }
     }
     //now add in editable python packages
-    if let Some(python) = &parsed_config.python {
+    if let Some(python) = parsed_config.python.as_ref().filter(|_| include_editables) {
         for (pkg_name, package) in &python.packages {
             if let Some(editable_path) = &package.editable_path {
                 let entry = todo.entry(editable_path.to_string()).or_default();
@@ -889,6 +1130,18 @@ fn perform_clones(flake_dir: &Path, parsed_config: &config::TofuConfigToml) -> R
             }
         }
     }
+    //and editable R packages
+    if let Some(r) = parsed_config.r.as_ref().filter(|_| include_editables) {
+        for (pkg_name, package) in &r.vcs_packages {
+            if let Some(editable_path) = &package.editable_path {
+                let entry = todo.entry(editable_path.clone()).or_default();
+                entry.insert(
+                    pkg_name.clone(),
+                    config::TofuPythonPackageSource::Vcs(package.url.clone()),
+                );
+            }
+        }
+    }
 
     for (target_dir, name_urls) in &todo {
         fs::create_dir_all(target_dir).context(format!("Could not create {target_dir}"))?;
@@ -899,24 +1152,48 @@ fn perform_clones(flake_dir: &Path, parsed_config: &config::TofuConfigToml) -> R
             HashMap::new()
         };
         let do_clones = |known_clones: &mut HashMap<String, String>| {
+            let mut source_ids = HashMap::new();
             for (name, source) in name_urls {
                 let known_source = known_clones.get(name).map_or("", String::as_str);
                 let final_dir: PathBuf = [target_dir, name].iter().collect();
-                let new_source_str = format!("{}", source.without_username_in_url());
-                if final_dir.exists() && !dir_empty(&final_dir)? && known_source != new_source_str
+                let new_source_str = clone_source_id(flake_dir, name, source)?;
+                // before we recorded the version, pypi editables were recorded by their
+                // constraint
+                let legacy_source_str = format!("{}", source.without_username_in_url());
+                if final_dir.exists()
+                    && !dir_empty(&final_dir)?
+                    && known_source != new_source_str
+                    && known_source != legacy_source_str
                 //empty dir is ok.
                 {
-                    let msg = format!(
-                            "Url changed for clone target: {target_dir}/{name}. Was '{known_source}' is now '{new_source_str}'.\n\
-                        Cowardly refusing to throw away old checkout in {final_dir:?}."
+                    let backup_dir = PathBuf::from(format!(
+                        "{}.before_refresh_{}",
+                        final_dir.to_string_lossy(),
+                        jiff::Zoned::now().strftime("%Y-%m-%d_%H-%M-%S")
+                    ));
+                    if refresh.iter().any(|x| x == name) {
+                        info!(
+                            "Refreshing {target_dir}/{name}: '{known_source}' -> '{new_source_str}'. Old checkout moved to {}",
+                            backup_dir.display()
+                        );
+                        fs::rename(&final_dir, &backup_dir)?;
+                    } else {
+                        let msg = format!(
+                            "Source changed for clone target: {target_dir}/{name}. Was '{known_source}' is now '{new_source_str}'.\n\
+                        Cowardly refusing to throw away old checkout in {}.\n\
+                        Rerun with --refresh-editable {name} to move it aside (to {name}.before_refresh_<date>) and fetch the new one.",
+                            final_dir.display()
                         );
-                    bail!(msg);
+                        bail!(msg);
+                    }
                 }
+                source_ids.insert(name, new_source_str);
             }
             for (name, url) in name_urls {
-                clone(flake_dir, target_dir, name, url, known_clones, do_jujustu).with_context(
-                    || format!("Cloning for {name} into {target_dir} from {url:?}"),
-                )?;
+                clone(flake_dir, target_dir, name, url, do_jujustu).with_context(|| {
+                    format!("Cloning for {name} into {target_dir} from {url:?}")
+                })?;
+                known_clones.insert(name.clone(), source_ids[name].clone());
             }
             Ok(())
         };
@@ -1020,10 +1297,43 @@ fn replace_env_vars(input: &str) -> String {
 }
 
 // deal with the editable packages.
+/// Which editable packages to reinstall regardless of their build metadata hash
+enum VenvRebuild {
+    All,
+    Some(Vec<SafePythonName>),
+}
+
+impl VenvRebuild {
+    fn contains(&self, pkg: &SafePythonName) -> bool {
+        match self {
+            VenvRebuild::All => true,
+            VenvRebuild::Some(pkgs) => pkgs.contains(pkg),
+        }
+    }
+}
+
+/// Hash the files that define an editable package's entry points / console scripts,
+/// so we know when to reinstall it
+fn editable_build_metadata_hash(target_dir: &Path) -> Result<String> {
+    let mut input = String::new();
+    for filename in ["pyproject.toml", "setup.py", "setup.cfg"] {
+        let path = target_dir.join(filename);
+        if path.exists() {
+            input.push_str(filename);
+            input.push('\0');
+            input.push_str(&ex::fs::read_to_string(&path)?);
+            input.push('\0');
+        }
+    }
+    Ok(sha256::digest(input))
+}
+
+#[allow(clippy::too_many_lines)]
 fn fill_venv(
     python_version: &str,
     python: &HashMap<SafePythonName, config::TofuPythonPackageDefinition>,
     flake_dir: &Path,
+    force_rebuild: &VenvRebuild,
 ) -> Result<()> {
     let venv_dir: PathBuf = flake_dir.join("venv").join(python_version);
     fs::create_dir_all(venv_dir.join("bin"))?;
@@ -1037,6 +1347,17 @@ fn fill_venv(
         .context("failed to find python binary in container")?;
     let target_python_str = target_python.to_string_lossy();
 
+    if let VenvRebuild::Some(pkgs) = force_rebuild {
+        for pkg in pkgs {
+            if python
+                .get(pkg)
+                .is_none_or(|spec| spec.editable_path.is_none())
+            {
+                bail!("{pkg} is not an editable python package");
+            }
+        }
+    }
+
     for (pkg, spec) in python
         .iter()
         .filter(|(_pkg, spec)| spec.editable_path.is_some())
@@ -1059,14 +1380,30 @@ fn fill_venv(
                 String::new()
             }
         };
-        if !venv_link.exists() {
+        let build_hash_file = venv_dir.join(format!("{pkg}.anysnake-build-hash"));
+        let build_hash = editable_build_metadata_hash(&target_dir)?;
+        if !build_hash_file.exists() && venv_link.exists() {
+            // installed before we tracked the build metadata, assume it's current
+            fs::write(&build_hash_file, &build_hash)?;
+        }
+        let build_hash_changed =
+            ex::fs::read_to_string(&build_hash_file).unwrap_or_default() != build_hash;
+        if !venv_link.exists() || build_hash_changed || force_rebuild.contains(pkg) {
             // so that changing python versions triggers a rebuild.
+            if build_hash_changed && venv_link.exists() {
+                info!("build metadata (pyproject.toml/setup.py/setup.cfg) of {pkg} changed, reinstalling");
+            }
             to_build.push((pkg, target_dir));
         } else if venv_used != target_python_str {
             to_rewrite_python_shebang.push((pkg, target_dir));
         }
     }
     for (safe_pkg, target_dir) in &to_build {
+        // otherwise a stale one would hide a failed install
+        let venv_link = venv_dir.join(format!("{safe_pkg}.venv-link"));
+        if venv_link.exists() {
+            fs::remove_file(&venv_link)?;
+        }
         install_editable_into_venv(
             safe_pkg,
             target_dir,
@@ -1076,6 +1413,11 @@ fn fill_venv(
             flake_dir,
             python_version,
         )?;
+        fs::write(
+            venv_dir.join(format!("{safe_pkg}.anysnake-build-hash")),
+            editable_build_metadata_hash(target_dir)?,
+        )
+        .context("build hash write failed")?;
     }
     if !to_rewrite_python_shebang.is_empty() {
         let mut old_pythons = HashSet::new();
@@ -1376,6 +1718,35 @@ fn test_nix_build_output_parsing() {
     let _: Vec<NixBuildOutput> = serde_json::from_str(json).unwrap();
 }
 
+#[test]
+fn test_unpack_tar_gz_strips_first_component() {
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::default(),
+    ));
+    for (path, content) in [
+        ("pkg-1.0/setup.py", "print('hello')"),
+        ("pkg-1.0/src/pkg/__init__.py", ""),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, path, content.as_bytes())
+            .unwrap();
+    }
+    let compressed = builder.into_inner().unwrap().finish().unwrap();
+    let td = tempfile::tempdir().unwrap();
+    unpack_tar_gz(compressed.as_slice(), td.path()).unwrap();
+    assert_eq!(
+        ex::fs::read_to_string(td.path().join("setup.py")).unwrap(),
+        "print('hello')"
+    );
+    assert!(td.path().join("src/pkg/__init__.py").exists());
+    assert!(!td.path().join("pkg-1.0").exists());
+}
+
 fn prefetch_flake(url_without_hash: &str) -> Result<String> {
     debug!("nix prefetching flake {}", &url_without_hash);
     run_without_ctrl_c(|| {
@@ -2,6 +2,7 @@
   buildSymlinkImage = {
     name,
     script,
+    python_envs ? {},
   }: let
   in rec {
     script_file = pkgs.writeScript "reqs.sh" script;
@@ -46,6 +47,10 @@
 
       ln -s $out/rootfs/bin $out/rootfs/usr/bin
 
+      # additional python environments. They can't go into the symlink forest above, their pyvenv.cfg would collide
+      mkdir -p $out/rootfs/envs
+      ${pkgs.lib.concatStringsSep "\n" (pkgs.lib.mapAttrsToList (env_name: env: "ln -s ${env} $out/rootfs/envs/${env_name}") python_envs)}
+
       mkdir -p $out/rootfs/etc/profile.d
       echo "export SSL_CERT_FILE=/etc/ssl/certs/ca-bundle.crt" >>$out/rootfs/etc/bashrc # singularity pulls that from the env otherwise apperantly
       echo "export SSL_CERT_DIR=/etc/ssl/certs" >>$out/rootfs/etc/bashrc # singularity pulls that from the env otherwise apperantly
@@ -62,9 +67,10 @@
   buildOCIimage = {
     name,
     script,
+    python_envs ? {},
   }: let
     symlink_image = buildSymlinkImage {
-      inherit name script;
+      inherit name script python_envs;
     };
     umoci = pkgs.umoci;
   in
@@ -93,7 +99,7 @@
 
       echo "rsyncing necessary nix paths"
       ${pkgs.rsync}/bin/rsync -arW --exclude=* --files-from=${
-        pkgs.writeClosure [symlink_image.script_file]
+        pkgs.writeClosure ([symlink_image.script_file] ++ builtins.attrValues python_envs)
       } / tmp-oci-unpack/rootfs/
 
 
@@ -0,0 +1,708 @@
+//! A small typed nix expression builder.
+//!
+//! flake.nix is assembled from these instead of filling placeholders in a template,
+//! so strings are always escaped and attribute names quoted where necessary.
+//! User supplied nix code (override attrs, overlays...) is passed through as [`Expr::Raw`].
+use anyhow::{bail, Result};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    /// verbatim nix code
+    Raw(String),
+    /// a variable or a (trusted) attribute path, e.g. `pkgs.hello`
+    Var(String),
+    Str(String),
+    /// an indented ''string'', with ${} interpolations. Give the content unindented.
+    IndentedStr(Vec<StrPart>),
+    Bool(bool),
+    Int(i64),
+    Null,
+    /// a path relative to flake.nix, e.g. `./rust/rust-toolchain.toml`
+    Path(String),
+    List(Vec<Expr>),
+    AttrSet(AttrSet),
+    Let(Vec<Binding>, Box<Expr>),
+    /// function application, `f a b`
+    Apply(Box<Expr>, Vec<Expr>),
+    /// attribute selection, `e.a."b-1.0".c`, optionally with an `or` default
+    Select(Box<Expr>, Vec<Key>, Option<Box<Expr>>),
+    /// `arg: body`
+    Lambda(String, Box<Expr>),
+    /// `{a, b}@name: body`
+    PatternLambda {
+        names: Vec<String>,
+        at: Option<String>,
+        body: Box<Expr>,
+    },
+    With(Box<Expr>, Box<Expr>),
+    /// `a ++ b`, `a // b`...
+    BinOp(Box<Expr>, &'static str, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AttrSet {
+    pub rec: bool,
+    pub bindings: Vec<Binding>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Binding {
+    /// `a.b = value;`
+    Attr(Vec<Key>, Expr),
+    /// `inherit a b;`
+    Inherit(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Key {
+    Name(String),
+    /// `${expr}`
+    Dynamic(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StrPart {
+    Lit(String),
+    Interp(Expr),
+}
+
+const KEYWORDS: &[&str] = &[
+    "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+];
+
+/// Is this usable as a bare nix identifier / attribute name?
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_'-".contains(c))
+        && !KEYWORDS.contains(&name)
+}
+
+/// Check a user supplied name that ends up as a nix variable (flake inputs are
+/// arguments of the outputs function), so it can't simply be quoted.
+pub fn checked_identifier<'a>(what: &str, name: &'a str) -> Result<&'a str> {
+    if !is_identifier(name) {
+        bail!(
+            "{what} '{name}' is not a valid nix identifier. Use letters, digits, '_', '-' and \"'\", start with a letter or '_', and avoid nix keywords"
+        );
+    }
+    Ok(name)
+}
+
+/// Parse a user supplied attribute path like `packages.${system}."hello-1.0"`.
+///
+/// Segments are identifiers, "quoted strings" or `${system}` - anything else
+/// (in particular other interpolations) is rejected.
+pub fn parse_attr_path(what: &str, path: &str) -> Result<Vec<Key>> {
+    let mut keys = Vec::new();
+    let mut rest = path;
+    loop {
+        if let Some(after) = rest.strip_prefix("${system}") {
+            keys.push(Key::Dynamic(Expr::var("system")));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('"') {
+            let Some(end) = after.find('"') else {
+                bail!("{what} '{path}': unterminated quoted attribute name");
+            };
+            let name = &after[..end];
+            if name.contains(['\\', '$']) {
+                bail!("{what} '{path}': quoted attribute names may not contain '\\' or '$'");
+            }
+            keys.push(Key::Name(name.to_string()));
+            rest = &after[end + 1..];
+        } else {
+            let end = rest.find('.').unwrap_or(rest.len());
+            keys.push(Key::Name(
+                checked_identifier(what, &rest[..end])?.to_string(),
+            ));
+            rest = &rest[end..];
+        }
+        match rest.strip_prefix('.') {
+            Some(after) => rest = after,
+            None if rest.is_empty() => break,
+            None => bail!("{what} '{path}': expected '.' between attribute names"),
+        }
+    }
+    Ok(keys)
+}
+
+/// A "double quoted" nix string
+pub fn escape_str(input: &str) -> String {
+    let mut out = String::with_capacity(input.len() + 2);
+    out.push('"');
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The body of an ''indented string''
+fn escape_indented_str(input: &str) -> String {
+    input.replace("''", "'''").replace("${", "''${")
+}
+
+/// Shift the continuation lines of (trimmed) multi line code so the least indented
+/// one is at the given indentation, keeping their relative indentation
+fn reindent(code: &str, pad: &str) -> String {
+    let min_indent = code
+        .lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let mut lines = code.lines();
+    let mut out = lines.next().unwrap_or_default().to_string();
+    for line in lines {
+        out.push('\n');
+        if !line.trim().is_empty() {
+            out.push_str(pad);
+            out.push_str(&line[min_indent..]);
+        }
+    }
+    out
+}
+
+fn format_key(key: &Key) -> String {
+    match key {
+        Key::Name(name) if is_identifier(name) => name.clone(),
+        Key::Name(name) => escape_str(name),
+        Key::Dynamic(expr) => format!("${{{expr}}}"),
+    }
+}
+
+impl From<&str> for Key {
+    fn from(name: &str) -> Self {
+        Key::Name(name.to_string())
+    }
+}
+
+impl From<String> for Key {
+    fn from(name: String) -> Self {
+        Key::Name(name)
+    }
+}
+
+impl Expr {
+    pub fn raw(code: impl Into<String>) -> Self {
+        Expr::Raw(code.into())
+    }
+
+    pub fn var(name: impl Into<String>) -> Self {
+        Expr::Var(name.into())
+    }
+
+    pub fn str(value: impl Into<String>) -> Self {
+        Expr::Str(value.into())
+    }
+
+    pub fn path(path: impl Into<String>) -> Self {
+        Expr::Path(path.into())
+    }
+
+    pub fn list<T: Into<Expr>>(entries: impl IntoIterator<Item = T>) -> Self {
+        Expr::List(entries.into_iter().map(Into::into).collect())
+    }
+
+    /// a list of nix strings
+    pub fn str_list<T: AsRef<str>>(entries: impl IntoIterator<Item = T>) -> Self {
+        Expr::List(entries.into_iter().map(|x| Expr::str(x.as_ref())).collect())
+    }
+
+    pub fn apply(self, args: impl IntoIterator<Item = Expr>) -> Self {
+        Expr::Apply(Box::new(self), args.into_iter().collect())
+    }
+
+    pub fn select<K: Into<Key>>(self, path: impl IntoIterator<Item = K>) -> Self {
+        Expr::Select(
+            Box::new(self),
+            path.into_iter().map(Into::into).collect(),
+            None,
+        )
+    }
+
+    pub fn select_or<K: Into<Key>>(self, path: impl IntoIterator<Item = K>, default: Expr) -> Self {
+        Expr::Select(
+            Box::new(self),
+            path.into_iter().map(Into::into).collect(),
+            Some(Box::new(default)),
+        )
+    }
+
+    pub fn lambda(arg: &str, body: Expr) -> Self {
+        Expr::Lambda(arg.to_string(), Box::new(body))
+    }
+
+    /// `final: prev: body`
+    pub fn overlay(body: Expr) -> Self {
+        Expr::lambda("final", Expr::lambda("prev", body))
+    }
+
+    pub fn let_in(bindings: Vec<Binding>, body: Expr) -> Self {
+        Expr::Let(bindings, Box::new(body))
+    }
+
+    pub fn with(scope: Expr, body: Expr) -> Self {
+        Expr::With(Box::new(scope), Box::new(body))
+    }
+
+    pub fn bin_op(self, op: &'static str, other: Expr) -> Self {
+        Expr::BinOp(Box::new(self), op, Box::new(other))
+    }
+
+    /// toml values map directly onto nix values (datetimes become strings)
+    pub fn from_toml(value: &toml::Value) -> Result<Self> {
+        Ok(match value {
+            toml::Value::String(value) => Expr::str(value),
+            toml::Value::Integer(value) => Expr::Int(*value),
+            toml::Value::Float(value) => {
+                if !value.is_finite() {
+                    bail!("nix has no representation for the float {value}");
+                }
+                // nix floats need a '.' before the exponent: 1.0e20, not 1e20
+                let mut repr = format!("{value:?}");
+                if let Some(exponent_start) = repr.find('e') {
+                    if !repr[..exponent_start].contains('.') {
+                        repr.insert_str(exponent_start, ".0");
+                    }
+                }
+                Expr::Raw(repr)
+            }
+            toml::Value::Boolean(value) => Expr::Bool(*value),
+            toml::Value::Datetime(value) => Expr::str(value.to_string()),
+            toml::Value::Array(entries) => Expr::list(
+                entries
+                    .iter()
+                    .map(Expr::from_toml)
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            toml::Value::Table(table) => table
+                .iter()
+                .map(|(key, value)| Ok((key.as_str(), Expr::from_toml(value)?)))
+                .collect::<Result<AttrSet>>()?
+                .into(),
+        })
+    }
+
+    /// Does this need no parentheses as function argument / selection base?
+    fn is_atomic(&self) -> bool {
+        match self {
+            Expr::Var(_)
+            | Expr::Str(_)
+            | Expr::IndentedStr(_)
+            | Expr::Bool(_)
+            | Expr::Null
+            | Expr::Path(_)
+            | Expr::List(_)
+            | Expr::AttrSet(_) => true,
+            Expr::Int(value) => *value >= 0,
+            Expr::Select(_, _, default) => default.is_none(),
+            Expr::Raw(code) => code.split('.').all(is_identifier),
+            _ => false,
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn render(&self, indent: usize, out: &mut String) {
+        let pad = "  ".repeat(indent);
+        match self {
+            Expr::Raw(code) => out.push_str(&reindent(code.trim(), &pad)),
+            Expr::Var(name) => out.push_str(name),
+            Expr::Str(value) => out.push_str(&escape_str(value)),
+            Expr::IndentedStr(parts) => {
+                let mut body = String::new();
+                for part in parts {
+                    match part {
+                        StrPart::Lit(text) => body.push_str(&escape_indented_str(text)),
+                        StrPart::Interp(expr) => {
+                            body.push_str("${");
+                            expr.render(indent + 1, &mut body);
+                            body.push('}');
+                        }
+                    }
+                }
+                out.push_str("''");
+                for line in body.lines() {
+                    out.push('\n');
+                    if !line.trim().is_empty() {
+                        out.push_str(&pad);
+                        out.push_str("  ");
+                        out.push_str(line);
+                    }
+                }
+                if body.ends_with('\n') {
+                    out.push('\n');
+                    out.push_str(&pad);
+                }
+                out.push_str("''");
+            }
+            Expr::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            Expr::Int(value) => out.push_str(&value.to_string()),
+            Expr::Null => out.push_str("null"),
+            Expr::Path(path) => {
+                if !path.starts_with("./") && !path.starts_with("../") && !path.starts_with('/') {
+                    out.push_str("./");
+                }
+                out.push_str(path);
+            }
+            Expr::List(entries) => {
+                if entries.is_empty() {
+                    out.push_str("[ ]");
+                    return;
+                }
+                let single_line = entries
+                    .iter()
+                    .map(|entry| entry.render_argument(indent))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                if single_line.len() < 80 && !single_line.contains('\n') {
+                    out.push_str("[ ");
+                    out.push_str(&single_line);
+                    out.push_str(" ]");
+                } else {
+                    out.push('[');
+                    for entry in entries {
+                        out.push('\n');
+                        out.push_str(&pad);
+                        out.push_str("  ");
+                        out.push_str(&entry.render_argument(indent + 1));
+                    }
+                    out.push('\n');
+                    out.push_str(&pad);
+                    out.push(']');
+                }
+            }
+            Expr::AttrSet(attrs) => attrs.render(indent, out),
+            Expr::Let(bindings, body) => {
+                out.push_str("let");
+                render_bindings(bindings, indent + 1, out);
+                out.push('\n');
+                out.push_str(&pad);
+                out.push_str("in\n");
+                out.push_str(&pad);
+                body.render(indent, out);
+            }
+            Expr::Apply(func, args) => {
+                out.push_str(&func.render_argument(indent));
+                for arg in args {
+                    out.push(' ');
+                    out.push_str(&arg.render_argument(indent));
+                }
+            }
+            Expr::Select(base, path, default) => {
+                out.push_str(&base.render_argument(indent));
+                for key in path {
+                    out.push('.');
+                    out.push_str(&format_key(key));
+                }
+                if let Some(default) = default {
+                    out.push_str(" or ");
+                    out.push_str(&default.render_argument(indent));
+                }
+            }
+            Expr::Lambda(arg, body) => {
+                out.push_str(arg);
+                out.push_str(": ");
+                body.render(indent, out);
+            }
+            Expr::PatternLambda { names, at, body } => {
+                out.push('{');
+                for name in names {
+                    out.push('\n');
+                    out.push_str(&pad);
+                    out.push_str("  ");
+                    out.push_str(name);
+                    out.push(',');
+                }
+                out.push('\n');
+                out.push_str(&pad);
+                out.push('}');
+                if let Some(at) = at {
+                    out.push('@');
+                    out.push_str(at);
+                }
+                out.push_str(":\n");
+                out.push_str(&pad);
+                body.render(indent, out);
+            }
+            Expr::With(scope, body) => {
+                out.push_str("with ");
+                out.push_str(&scope.render_argument(indent));
+                out.push_str("; ");
+                body.render(indent, out);
+            }
+            Expr::BinOp(left, op, right) => {
+                out.push_str(&left.render_argument(indent));
+                out.push(' ');
+                out.push_str(op);
+                out.push(' ');
+                out.push_str(&right.render_argument(indent));
+            }
+        }
+    }
+
+    /// render, with parentheses unless atomic
+    fn render_argument(&self, indent: usize) -> String {
+        let mut out = String::new();
+        if self.is_atomic() {
+            self.render(indent, &mut out);
+        } else {
+            out.push('(');
+            self.render(indent, &mut out);
+            out.push(')');
+        }
+        out
+    }
+}
+
+fn render_bindings(bindings: &[Binding], indent: usize, out: &mut String) {
+    let pad = "  ".repeat(indent);
+    for binding in bindings {
+        out.push('\n');
+        out.push_str(&pad);
+        match binding {
+            Binding::Attr(path, value) => {
+                out.push_str(&path.iter().map(format_key).collect::<Vec<_>>().join("."));
+                out.push_str(" = ");
+                value.render(indent, out);
+                out.push(';');
+            }
+            Binding::Inherit(names) => {
+                out.push_str("inherit ");
+                out.push_str(&names.join(" "));
+                out.push(';');
+            }
+        }
+    }
+}
+
+impl AttrSet {
+    pub fn new() -> Self {
+        AttrSet::default()
+    }
+
+    pub fn rec() -> Self {
+        AttrSet {
+            rec: true,
+            bindings: Vec::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn attr(mut self, name: impl Into<Key>, value: Expr) -> Self {
+        self.bindings.push(Binding::Attr(vec![name.into()], value));
+        self
+    }
+
+    /// `a.b.c = value;`
+    #[must_use]
+    pub fn attr_path<K: Into<Key>>(
+        mut self,
+        path: impl IntoIterator<Item = K>,
+        value: Expr,
+    ) -> Self {
+        self.bindings.push(Binding::Attr(
+            path.into_iter().map(Into::into).collect(),
+            value,
+        ));
+        self
+    }
+
+    #[must_use]
+    pub fn inherit(mut self, names: &[&str]) -> Self {
+        self.bindings.push(Binding::Inherit(
+            names.iter().map(ToString::to_string).collect(),
+        ));
+        self
+    }
+
+    pub fn push(&mut self, name: impl Into<Key>, value: Expr) {
+        self.bindings.push(Binding::Attr(vec![name.into()], value));
+    }
+
+    fn render(&self, indent: usize, out: &mut String) {
+        if self.rec {
+            out.push_str("rec ");
+        }
+        if self.bindings.is_empty() {
+            out.push_str("{ }");
+            return;
+        }
+        out.push('{');
+        render_bindings(&self.bindings, indent + 1, out);
+        out.push('\n');
+        out.push_str(&"  ".repeat(indent));
+        out.push('}');
+    }
+}
+
+impl<K: Into<Key>> FromIterator<(K, Expr)> for AttrSet {
+    fn from_iter<I: IntoIterator<Item = (K, Expr)>>(iter: I) -> Self {
+        AttrSet {
+            rec: false,
+            bindings: iter
+                .into_iter()
+                .map(|(name, value)| Binding::Attr(vec![name.into()], value))
+                .collect(),
+        }
+    }
+}
+
+impl From<AttrSet> for Expr {
+    fn from(attrs: AttrSet) -> Self {
+        Expr::AttrSet(attrs)
+    }
+}
+
+impl StrPart {
+    pub fn lit(text: impl Into<String>) -> Self {
+        StrPart::Lit(text.into())
+    }
+
+    pub fn interp(expr: Expr) -> Self {
+        StrPart::Interp(expr)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = String::new();
+        self.render(0, &mut out);
+        f.write_str(&out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escaping() {
+        assert_eq!(
+            Expr::str("say \"hi\" to ${USER}\\n\n").to_string(),
+            r#""say \"hi\" to \${USER}\\n\n""#
+        );
+        assert_eq!(Expr::str("costs $5").to_string(), "\"costs $5\"");
+        assert_eq!(
+            Expr::IndentedStr(vec![
+                StrPart::lit("echo '' ${HOME}\n"),
+                StrPart::interp(Expr::var("pkgs.hello")),
+                StrPart::lit("/bin/hello\n"),
+            ])
+            .to_string(),
+            "''\n  echo ''' ''${HOME}\n  ${pkgs.hello}/bin/hello\n''"
+        );
+    }
+
+    #[test]
+    fn test_user_supplied_names() {
+        assert!(checked_identifier("flake name", "my-flake_2").is_ok());
+        assert!(checked_identifier("flake name", "2fast").is_err());
+        assert!(checked_identifier("flake name", "in").is_err());
+        assert!(checked_identifier("flake name", "a\"; evil = \"").is_err());
+
+        let keys = parse_attr_path("package", "packages.${system}.\"hello-1.0\".out").unwrap();
+        assert_eq!(
+            Expr::var("hello").select(keys).to_string(),
+            "hello.packages.${system}.\"hello-1.0\".out"
+        );
+        for bad in [
+            "",
+            "packages..x",
+            "packages.${builtins.abort \"x\"}",
+            "\"unterminated",
+            "\"a${b}\"",
+            "x y",
+            "x;y",
+        ] {
+            assert!(parse_attr_path("package", bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let table: toml::Table = toml::from_str(
+            r#"
+            cudaSupport = false
+            "cuda.version" = "12.4"
+            jobs = [2, -1, 0.5]
+            nested = { a = "${x}" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            Expr::from_toml(&toml::Value::Table(table))
+                .unwrap()
+                .to_string(),
+            "{\n  \"cuda.version\" = \"12.4\";\n  cudaSupport = false;\n  jobs = [ 2 (-1) (0.5) ];\n  nested = {\n    a = \"\\${x}\";\n  };\n}"
+        );
+        for (value, expected) in [(1e20, "1.0e20"), (1.5e-7, "1.5e-7"), (2.0, "2.0")] {
+            assert_eq!(
+                Expr::from_toml(&toml::Value::Float(value))
+                    .unwrap()
+                    .to_string(),
+                expected
+            );
+        }
+        for value in [f64::INFINITY, f64::NAN] {
+            assert!(Expr::from_toml(&toml::Value::Float(value)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_attribute_names() {
+        let attrs: AttrSet = [
+            ("plain", Expr::Null),
+            ("with-dash", Expr::Bool(true)),
+            ("1.80.0", Expr::Bool(false)),
+            ("in", Expr::list([Expr::str("a"), Expr::var("b")])),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            Expr::from(attrs).to_string(),
+            "{\n  plain = null;\n  with-dash = true;\n  \"1.80.0\" = false;\n  \"in\" = [ \"a\" b ];\n}"
+        );
+        assert_eq!(
+            Expr::var("pkgs.rust-bin")
+                .select(["stable", "1.80.0", "minimal"])
+                .to_string(),
+            "pkgs.rust-bin.stable.\"1.80.0\".minimal"
+        );
+        assert_eq!(
+            Expr::var("hello")
+                .select([Key::from("packages"), Key::Dynamic(Expr::var("system"))])
+                .to_string(),
+            "hello.packages.${system}"
+        );
+    }
+
+    #[test]
+    fn test_nesting() {
+        let expr = Expr::var("pkgs.runCommand").apply([
+            Expr::str("name"),
+            AttrSet::new().into(),
+            Expr::let_in(
+                vec![Binding::Attr(vec!["x".into()], Expr::path("rust/x.toml"))],
+                Expr::overlay(Expr::var("x").select_or(["y"], Expr::Null)),
+            ),
+        ]);
+        assert_eq!(
+            expr.to_string(),
+            "pkgs.runCommand \"name\" { } (let\n  x = ./rust/x.toml;\nin\nfinal: prev: x.y or null)"
+        );
+    }
+}
@@ -0,0 +1,520 @@
+use anyhow::{Context, Result};
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use version_compare::{Cmp, Version};
+
+use anysnake2::util::get_proxy_req;
+
+/// pypi/uv normalize package names, so must we
+pub fn normalize_name(name: &str) -> String {
+    name.to_lowercase().replace(['_', '.'], "-")
+}
+
+pub fn fetch_pypi_json(name: &str, version: Option<&str>) -> Result<serde_json::Value> {
+    let url = match version {
+        Some(version) => format!("https://pypi.org/pypi/{name}/{version}/json"),
+        None => format!("https://pypi.org/pypi/{name}/json"),
+    };
+    debug!("querying {url}");
+    let json = get_proxy_req()?
+        .get(&url)
+        .call()
+        .with_context(|| format!("Failed to query {url}"))?
+        .body_mut()
+        .read_to_string()?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+/// Split a PEP 508 requirement (`numpy (>=1.20); python_version < "3.10"`)
+/// into normalized name, specifier and marker
+pub fn parse_requirement(requirement: &str) -> (String, String, Option<String>) {
+    let (req, marker) = match requirement.split_once(';') {
+        Some((req, marker)) => (req.trim(), Some(marker.trim().to_string())),
+        None => (requirement.trim(), None),
+    };
+    let name_end = req
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'))
+        .unwrap_or(req.len());
+    let name = normalize_name(&req[..name_end]);
+    let mut rest = req[name_end..].trim();
+    if rest.starts_with('[') {
+        rest = rest.split_once(']').map_or("", |x| x.1).trim();
+    }
+    let specifier = rest
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .trim()
+        .to_string();
+    (name, specifier, marker)
+}
+
+fn is_prerelease(version: &str) -> bool {
+    regex::Regex::new(r"\d(a|b|rc|c|\.?dev)\d*")
+        .unwrap()
+        .is_match(version)
+}
+
+/// version -> first upload date, excluding yanked and pre-releases
+pub fn pypi_release_dates(json: &serde_json::Value) -> BTreeMap<String, String> {
+    let mut res = BTreeMap::new();
+    if let Some(releases) = json["releases"].as_object() {
+        for (version, files) in releases {
+            if is_prerelease(version) {
+                continue;
+            }
+            let Some(files) = files.as_array() else {
+                continue;
+            };
+            let first_upload = files
+                .iter()
+                .filter(|f| !f["yanked"].as_bool().unwrap_or(false))
+                .filter_map(|f| f["upload_time_iso_8601"].as_str())
+                .map(|x| x.chars().take(10).collect::<String>())
+                .min();
+            if let Some(first_upload) = first_upload {
+                res.insert(version.clone(), first_upload);
+            }
+        }
+    }
+    res
+}
+
+pub fn newest_version<'a>(versions: impl Iterator<Item = &'a String>) -> Option<&'a String> {
+    versions
+        .filter_map(|v| Version::from(v).map(|parsed| (parsed, v)))
+        .max_by(|(a, _), (b, _)| a.compare(b).ord().unwrap())
+        .map(|(_, v)| v)
+}
+
+/// The newest release uploaded on or before `date` (yyyy-mm-dd)
+pub fn newest_version_before(
+    release_dates: &BTreeMap<String, String>,
+    date: &str,
+) -> Option<String> {
+    newest_version(
+        release_dates
+            .iter()
+            .filter(|(_, release_date)| release_date.as_str() <= date)
+            .map(|(version, _)| version),
+    )
+    .cloned()
+}
+
+/// Does `version` satisfy a PEP 440 specifier set (`>=1.2,<2`, `~=1.4`, `==1.*`)?
+/// Clauses we can't parse count as satisfied - uv will complain about them.
+pub fn version_satisfies(version: &str, specifier: &str) -> bool {
+    let Some(parsed) = Version::from(version) else {
+        return true;
+    };
+    specifier
+        .split(',')
+        .map(str::trim)
+        .filter(|clause| !clause.is_empty() && *clause != "*")
+        .all(|clause| {
+            let op_end = clause
+                .find(|c: char| !"<>=!~".contains(c))
+                .unwrap_or(clause.len());
+            let (op, wanted) = (&clause[..op_end], clause[op_end..].trim());
+            if let Some(prefix) = wanted.strip_suffix(".*") {
+                let matches = version == prefix || version.starts_with(&format!("{prefix}."));
+                return match op {
+                    "==" => matches,
+                    "!=" => !matches,
+                    _ => true,
+                };
+            }
+            let Some(wanted_parsed) = Version::from(wanted) else {
+                return true;
+            };
+            let ord = parsed.compare(&wanted_parsed);
+            match op {
+                "" | "==" | "===" => ord == Cmp::Eq,
+                "!=" => ord != Cmp::Eq,
+                ">=" => ord != Cmp::Lt,
+                "<=" => ord != Cmp::Gt,
+                ">" => ord == Cmp::Gt,
+                "<" => ord == Cmp::Lt,
+                // ~=1.4.2 is >=1.4.2, ==1.4.*
+                "~=" => {
+                    let prefix = wanted.rsplit_once('.').map_or(wanted, |x| x.0);
+                    ord != Cmp::Lt && version_satisfies(version, &format!("=={prefix}.*"))
+                }
+                _ => true,
+            }
+        })
+}
+
+/// The newest release on or before `date` (yyyy-mm-dd) that satisfies `specifier`
+fn newest_matching_version_before(
+    release_dates: &BTreeMap<String, String>,
+    date: &str,
+    specifier: &str,
+) -> Option<String> {
+    newest_version(
+        release_dates
+            .iter()
+            .filter(|(version, release_date)| {
+                release_date.as_str() <= date && version_satisfies(version, specifier)
+            })
+            .map(|(version, _)| version),
+    )
+    .cloned()
+}
+
+#[derive(Debug, PartialEq)]
+enum MarkerToken {
+    Str(String),
+    Word(String),
+    Op(String),
+    Open,
+    Close,
+}
+
+fn tokenize_marker(marker: &str) -> Option<Vec<MarkerToken>> {
+    let mut res = Vec::new();
+    let mut chars = marker.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '(' || c == ')' {
+            chars.next();
+            res.push(if c == '(' {
+                MarkerToken::Open
+            } else {
+                MarkerToken::Close
+            });
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let value: String = chars.by_ref().take_while(|x| *x != c).collect();
+            res.push(MarkerToken::Str(value));
+        } else if "<>=!~".contains(c) {
+            let mut op = String::new();
+            while let Some(&c) = chars.peek().filter(|c| "<>=!~".contains(**c)) {
+                op.push(c);
+                chars.next();
+            }
+            res.push(MarkerToken::Op(op));
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            let mut word = String::new();
+            while let Some(&c) = chars
+                .peek()
+                .filter(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '.')
+            {
+                word.push(c);
+                chars.next();
+            }
+            res.push(MarkerToken::Word(word));
+        } else {
+            return None;
+        }
+    }
+    Some(res)
+}
+
+/// Evaluates PEP 508 markers for the linux (cpython) environment we build.
+/// Unknown variables and unparsable markers count as true, `extra` as false - we don't install extras.
+struct MarkerEvaluator<'a> {
+    tokens: &'a [MarkerToken],
+    pos: usize,
+    python_version: &'a str,
+    python_full_version: &'a str,
+}
+
+impl MarkerEvaluator<'_> {
+    fn or_expr(&mut self) -> Option<bool> {
+        let mut res = self.and_expr()?;
+        while self.tokens.get(self.pos) == Some(&MarkerToken::Word("or".to_string())) {
+            self.pos += 1;
+            res |= self.and_expr()?;
+        }
+        Some(res)
+    }
+
+    fn and_expr(&mut self) -> Option<bool> {
+        let mut res = self.atom()?;
+        while self.tokens.get(self.pos) == Some(&MarkerToken::Word("and".to_string())) {
+            self.pos += 1;
+            res &= self.atom()?;
+        }
+        Some(res)
+    }
+
+    fn atom(&mut self) -> Option<bool> {
+        if self.tokens.get(self.pos) == Some(&MarkerToken::Open) {
+            self.pos += 1;
+            let res = self.or_expr()?;
+            if self.tokens.get(self.pos) != Some(&MarkerToken::Close) {
+                return None;
+            }
+            self.pos += 1;
+            return Some(res);
+        }
+        let left = self.tokens.get(self.pos)?;
+        let op = match (
+            self.tokens.get(self.pos + 1)?,
+            self.tokens.get(self.pos + 2),
+        ) {
+            (MarkerToken::Op(op), _) => op.clone(),
+            (MarkerToken::Word(word), _) if word == "in" => "in".to_string(),
+            (MarkerToken::Word(word), Some(MarkerToken::Word(word2)))
+                if word == "not" && word2 == "in" =>
+            {
+                self.pos += 1;
+                "not in".to_string()
+            }
+            _ => return None,
+        };
+        let right = self.tokens.get(self.pos + 2)?;
+        self.pos += 3;
+        Some(self.compare(left, &op, right))
+    }
+
+    /// None for variables we don't know
+    fn value(&self, token: &MarkerToken) -> Option<String> {
+        match token {
+            MarkerToken::Str(value) => Some(value.clone()),
+            MarkerToken::Word(variable) => match variable.as_str() {
+                "python_version" => Some(self.python_version.to_string()),
+                "python_full_version" => Some(self.python_full_version.to_string()),
+                "sys_platform" => Some("linux".to_string()),
+                "platform_system" => Some("Linux".to_string()),
+                "os_name" => Some("posix".to_string()),
+                "implementation_name" => Some("cpython".to_string()),
+                "platform_python_implementation" => Some("CPython".to_string()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn compare(&self, left: &MarkerToken, op: &str, right: &MarkerToken) -> bool {
+        let is_variable = |token: &MarkerToken, name: &str| matches!(token, MarkerToken::Word(word) if word == name);
+        if is_variable(left, "extra") || is_variable(right, "extra") {
+            return false;
+        }
+        let (Some(left_value), Some(right_value)) = (self.value(left), self.value(right)) else {
+            return true;
+        };
+        let is_version = |token: &MarkerToken| {
+            is_variable(token, "python_version") || is_variable(token, "python_full_version")
+        };
+        match op {
+            "in" => right_value.contains(&left_value),
+            "not in" => !right_value.contains(&left_value),
+            _ if is_version(left) => version_satisfies(&left_value, &format!("{op}{right_value}")),
+            _ if is_version(right) => {
+                let flipped = match op {
+                    "<" => ">",
+                    "<=" => ">=",
+                    ">" => "<",
+                    ">=" => "<=",
+                    other => other,
+                };
+                version_satisfies(&right_value, &format!("{flipped}{left_value}"))
+            }
+            "==" | "===" => left_value == right_value,
+            "!=" => left_value != right_value,
+            _ => true,
+        }
+    }
+}
+
+/// Does a requirement's marker apply on linux with this python `version` (3.12 or 3.12.1)?
+pub fn marker_applies(marker: &str, python_version: &str) -> bool {
+    let Some(tokens) = tokenize_marker(marker) else {
+        return true;
+    };
+    let major_minor = python_version
+        .split('.')
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(".");
+    let mut evaluator = MarkerEvaluator {
+        tokens: &tokens,
+        pos: 0,
+        python_version: &major_minor,
+        python_full_version: python_version,
+    };
+    match evaluator.or_expr() {
+        Some(res) if evaluator.pos == tokens.len() => res,
+        _ => true,
+    }
+}
+
+/// The (name, specifier) of the requirements that apply with this python version.
+/// A dependency listed more than once (under different markers) uses its first applicable entry.
+fn applicable_requirements<'a>(
+    requires_dist: impl Iterator<Item = &'a str>,
+    python_version: &str,
+) -> Vec<(String, String)> {
+    let mut seen = HashSet::new();
+    requires_dist
+        .map(parse_requirement)
+        .filter(|(_, _, marker)| {
+            marker
+                .as_ref()
+                .is_none_or(|marker| marker_applies(marker, python_version))
+        })
+        .filter(|(name, _, _)| seen.insert(name.clone()))
+        .map(|(name, specifier, _)| (name, specifier))
+        .collect()
+}
+
+/// Resolve a per package `ecosystem_date` into exact versions for ancient-poetry's exclusion list.
+///
+/// The package is pinned to it's newest release on or before `package_date`
+/// that satisfies the user's `constraint`.
+/// It's dependencies (those whose markers apply to `python_version` on linux, no extras) are pinned the same way (recursively, against the
+/// dependant's specifier) if the version ancient-poetry would pick at `global_date`
+/// does not satisfy it - either because there was no release yet, or because it's too old.
+/// Dependencies that are fine at the global date are left to ancient-poetry.
+pub fn ecosystem_date_pins(
+    name: &str,
+    constraint: &str,
+    package_date: &str,
+    global_date: &str,
+    python_version: &str,
+) -> Result<BTreeMap<String, String>> {
+    let mut res = BTreeMap::new();
+    let mut seen = HashSet::new();
+    let mut todo = VecDeque::new();
+    todo.push_back((normalize_name(name), constraint.to_string()));
+    while let Some((current, specifier)) = todo.pop_front() {
+        if !seen.insert(current.clone()) {
+            continue;
+        }
+        let json = fetch_pypi_json(&current, None)?;
+        let release_dates = pypi_release_dates(&json);
+        let is_root = res.is_empty();
+        if !is_root
+            && newest_version_before(&release_dates, global_date)
+                .is_some_and(|version| version_satisfies(&version, &specifier))
+        {
+            continue;
+        }
+        let version = newest_matching_version_before(&release_dates, package_date, &specifier)
+            .with_context(|| {
+                format!("No release of {current} matching '{specifier}' on PyPI on or before {package_date}")
+            })?;
+        debug!("ecosystem_date {package_date} for {name}: pinning {current}={version}");
+        let release_json = fetch_pypi_json(&current, Some(&version))?;
+        let requires_dist = release_json["info"]["requires_dist"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        todo.extend(applicable_requirements(
+            requires_dist.iter().filter_map(serde_json::Value::as_str),
+            python_version,
+        ));
+        res.insert(current, version);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_requirement() {
+        assert_eq!(
+            parse_requirement("numpy (>=1.20.3)"),
+            ("numpy".to_string(), ">=1.20.3".to_string(), None)
+        );
+        assert_eq!(
+            parse_requirement("python_dateutil>=2.8.1; python_version < \"3.10\""),
+            (
+                "python-dateutil".to_string(),
+                ">=2.8.1".to_string(),
+                Some("python_version < \"3.10\"".to_string())
+            )
+        );
+        assert_eq!(
+            parse_requirement("requests[socks]"),
+            ("requests".to_string(), String::new(), None)
+        );
+    }
+
+    #[test]
+    fn test_marker_applies() {
+        assert!(marker_applies("python_version < \"3.13\"", "3.12"));
+        assert!(!marker_applies("python_version >= \"3.13\"", "3.12.1"));
+        assert!(marker_applies("\"3.10\" <= python_version", "3.12"));
+        assert!(!marker_applies("sys_platform == \"win32\"", "3.12"));
+        assert!(marker_applies(
+            "sys_platform != 'win32' and (python_full_version >= '3.12.1' or os_name == 'nt')",
+            "3.12.1"
+        ));
+        assert!(!marker_applies(
+            "platform_system in 'Windows Darwin'",
+            "3.12"
+        ));
+        assert!(!marker_applies("extra == \"test\"", "3.12"));
+        assert!(marker_applies("platform_machine == \"x86_64\"", "3.12"));
+    }
+
+    #[test]
+    fn test_applicable_requirements() {
+        let requires_dist = [
+            "numpy>=1.26; python_version >= \"3.12\"",
+            "numpy>=1.22; python_version < \"3.12\"",
+            "pywin32; sys_platform == \"win32\"",
+            "pytest; extra == \"test\"",
+            "packaging",
+        ];
+        assert_eq!(
+            applicable_requirements(requires_dist.into_iter(), "3.11"),
+            vec![
+                ("numpy".to_string(), ">=1.22".to_string()),
+                ("packaging".to_string(), String::new()),
+            ]
+        );
+        assert_eq!(
+            applicable_requirements(requires_dist.into_iter(), "3.12")[0],
+            ("numpy".to_string(), ">=1.26".to_string())
+        );
+    }
+
+    #[test]
+    fn test_newest_version_before() {
+        let json = serde_json::json!({
+            "releases": {
+                "1.9.0": [{"upload_time_iso_8601": "2023-01-10T10:00:00Z", "yanked": false}],
+                "1.10.0": [{"upload_time_iso_8601": "2024-03-01T10:00:00Z", "yanked": false}],
+                "1.10.1rc1": [{"upload_time_iso_8601": "2024-04-01T10:00:00Z", "yanked": false}],
+                "1.10.1": [{"upload_time_iso_8601": "2024-05-01T10:00:00Z", "yanked": true}],
+                "1.11.0": [{"upload_time_iso_8601": "2024-07-01T10:00:00Z", "yanked": false}],
+            }
+        });
+        let dates = pypi_release_dates(&json);
+        assert!(!dates.contains_key("1.10.1rc1"));
+        assert!(!dates.contains_key("1.10.1"));
+        assert_eq!(
+            newest_version_before(&dates, "2024-06-01"),
+            Some("1.10.0".to_string())
+        );
+        assert_eq!(
+            newest_version_before(&dates, "2023-01-10"),
+            Some("1.9.0".to_string())
+        );
+        assert_eq!(newest_version_before(&dates, "2022-01-01"), None);
+        assert_eq!(
+            newest_matching_version_before(&dates, "2024-08-01", "<1.11"),
+            Some("1.10.0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_version_satisfies() {
+        assert!(version_satisfies("1.9.0", "<1.10"));
+        assert!(!version_satisfies("1.10.0", "<1.10"));
+        assert!(version_satisfies("1.10.0", ">=1.2, <2"));
+        assert!(version_satisfies("1.4.5", "~=1.4.2"));
+        assert!(!version_satisfies("1.5.0", "~=1.4.2"));
+        assert!(version_satisfies("2.1", "==2.*"));
+        assert!(!version_satisfies("2.1", "!=2.*"));
+        assert!(version_satisfies("1.2.3", "1.2.3"));
+        assert!(version_satisfies("1.2.3", ""));
+    }
+}
@@ -0,0 +1,388 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use version_compare::{compare_to, Cmp};
+
+/// packages that ship with R itself and never need to be requested from nixR
+pub const R_BASE_PACKAGES: &[&str] = &[
+    "R",
+    "base",
+    "compiler",
+    "datasets",
+    "grDevices",
+    "graphics",
+    "grid",
+    "methods",
+    "parallel",
+    "splines",
+    "stats",
+    "stats4",
+    "tcltk",
+    "tools",
+    "utils",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RDescription {
+    pub package: String,
+    pub version: String,
+    /// Depends, Imports and `LinkingTo`, without R and it's base packages
+    pub dependencies: Vec<String>,
+}
+
+/// parse the debian-control-file style fields of an R package DESCRIPTION
+pub fn parse_dcf_fields(raw: &str) -> BTreeMap<String, String> {
+    let mut fields: BTreeMap<String, String> = BTreeMap::new();
+    let mut last_key: Option<String> = None;
+    for line in raw.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some(key) = &last_key {
+                let value = fields.get_mut(key).unwrap();
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            fields.insert(key.trim().to_string(), value.trim().to_string());
+            last_key = Some(key.trim().to_string());
+        }
+    }
+    fields
+}
+
+/// An (operator, version) pair, as in `(>= 1.0.0)`
+pub type VersionConstraint = (String, String);
+
+/// `Rcpp (>= 1.0.0), methods` -> `[(Rcpp, Some((>=, 1.0.0))), (methods, None)]`
+pub fn parse_dependency_constraints(input: &str) -> Vec<(String, Option<VersionConstraint>)> {
+    input
+        .split(',')
+        .map(|entry| match entry.split_once('(') {
+            Some((name, constraint)) => {
+                let constraint = constraint.trim().trim_end_matches(')').trim();
+                let version_start = constraint
+                    .find(|c: char| c.is_ascii_digit())
+                    .unwrap_or(constraint.len());
+                let (op, version) = constraint.split_at(version_start);
+                (
+                    name.trim().to_string(),
+                    Some((op.trim().to_string(), version.trim().to_string())),
+                )
+            }
+            None => (entry.trim().to_string(), None),
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// `Rcpp (>= 1.0.0), methods` -> `[Rcpp, methods]`
+pub fn parse_dependency_list(input: &str) -> Vec<String> {
+    parse_dependency_constraints(input)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Does `version` fulfill the constraint? Unparsable constraints are considered fulfilled.
+pub fn version_satisfies(version: &str, constraint: &VersionConstraint) -> bool {
+    let (op, required) = constraint;
+    let Ok(op) = Cmp::from_sign(op) else {
+        return true;
+    };
+    compare_to(version, required, op).unwrap_or(true)
+}
+
+/// R package names are letters, digits and '.', start with a letter and don't end in a '.'
+pub fn validate_r_package_name(name: &str) -> Result<()> {
+    let valid = regex::Regex::new(r"^[A-Za-z][A-Za-z0-9.]*[A-Za-z0-9]$").unwrap();
+    if !valid.is_match(name) {
+        bail!("'{name}' is not a valid R package name. R package names consist of letters, digits and '.', start with a letter and do not end in '.'");
+    }
+    Ok(())
+}
+
+/// `R.additional_packages` keys are `name_version`, e.g. `mypkg_1.0-2`
+pub fn validate_r_name_version(key: &str) -> Result<()> {
+    let Some((name, version)) = key.split_once('_') else {
+        bail!("'{key}' does not follow the 'name_version' schema (e.g. 'mypkg_1.0.2')");
+    };
+    validate_r_package_name(name)?;
+    let valid_version = regex::Regex::new(r"^[0-9]+([.-][0-9]+)*$").unwrap();
+    if !valid_version.is_match(version) {
+        bail!("'{key}': '{version}' is not a valid R package version");
+    }
+    Ok(())
+}
+
+pub fn parse_description(raw: &str) -> Result<RDescription> {
+    let fields = parse_dcf_fields(raw);
+    let package = fields
+        .get("Package")
+        .context("DESCRIPTION had no Package field")?
+        .clone();
+    let version = fields
+        .get("Version")
+        .context("DESCRIPTION had no Version field")?
+        .clone();
+    let mut dependencies: Vec<String> = ["Depends", "Imports", "LinkingTo"]
+        .iter()
+        .filter_map(|key| fields.get(*key))
+        .flat_map(|value| parse_dependency_list(value))
+        .filter(|name| !R_BASE_PACKAGES.contains(&name.as_str()))
+        .collect();
+    dependencies.sort();
+    dependencies.dedup();
+    Ok(RDescription {
+        package,
+        version,
+        dependencies,
+    })
+}
+
+/// An R package requested by a renv.lock or DESCRIPTION file
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImportedRPackage {
+    pub name: String,
+    pub constraint: Option<VersionConstraint>,
+    /// anysnake2 url for packages that renv installed from github
+    pub github_url: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImportedR {
+    pub r_constraint: Option<VersionConstraint>,
+    pub packages: Vec<ImportedRPackage>,
+}
+
+pub fn parse_renv_lock(raw: &str) -> Result<ImportedR> {
+    let parsed: serde_json::Value = serde_json::from_str(raw).context("renv.lock was not json")?;
+    let r_constraint = parsed["R"]["Version"]
+        .as_str()
+        .map(|version| ("==".to_string(), version.to_string()));
+    let mut packages = Vec::new();
+    for (name, info) in parsed["Packages"]
+        .as_object()
+        .context("renv.lock had no Packages")?
+    {
+        if R_BASE_PACKAGES.contains(&name.as_str()) {
+            continue;
+        }
+        let github_url = if info["Source"].as_str() == Some("GitHub") {
+            let user = info["RemoteUsername"].as_str();
+            let repo = info["RemoteRepo"].as_str();
+            let sha = info["RemoteSha"].as_str();
+            match (user, repo, sha) {
+                (Some(user), Some(repo), Some(sha)) => {
+                    let reference = info["RemoteRef"].as_str().unwrap_or("HEAD");
+                    Some(format!("github:{user}/{repo}/{reference}/{sha}"))
+                }
+                _ => None,
+            }
+        } else {
+            None
+        };
+        packages.push(ImportedRPackage {
+            name: name.clone(),
+            constraint: info["Version"]
+                .as_str()
+                .map(|version| ("==".to_string(), version.to_string())),
+            github_url,
+        });
+    }
+    Ok(ImportedR {
+        r_constraint,
+        packages,
+    })
+}
+
+pub fn parse_description_for_import(raw: &str) -> ImportedR {
+    let fields = parse_dcf_fields(raw);
+    let mut r_constraint = None;
+    let mut packages = Vec::new();
+    for (name, constraint) in ["Depends", "Imports", "LinkingTo"]
+        .iter()
+        .filter_map(|key| fields.get(*key))
+        .flat_map(|value| parse_dependency_constraints(value))
+    {
+        if name == "R" {
+            r_constraint = constraint;
+        } else if !R_BASE_PACKAGES.contains(&name.as_str())
+            && !packages.iter().any(|p: &ImportedRPackage| p.name == name)
+        {
+            packages.push(ImportedRPackage {
+                name,
+                constraint,
+                github_url: None,
+            });
+        }
+    }
+    ImportedR {
+        r_constraint,
+        packages,
+    }
+}
+
+/// One entry of nixR's generated/readme.md date table
+#[derive(Debug, PartialEq, Eq)]
+pub struct NixRDate {
+    pub date: String,
+    pub r_version: Option<String>,
+    pub bioconductor_version: Option<String>,
+}
+
+/// Parse the dates (and R / Bioconductor versions) from nixR's generated/readme.md.
+///
+/// Each line with a date is an entry, the first x.y.z on it is the R version,
+/// the first x.y the Bioconductor version. Sorted by date.
+pub fn parse_nixr_dates(readme: &str) -> Vec<NixRDate> {
+    let date_re = regex::Regex::new(r"\d{4}-\d{2}-\d{2}").unwrap();
+    let version_re = regex::Regex::new(r"(?:^|[^\d.-])(\d+\.\d+(?:\.\d+)?)(?:$|[^\d.-])").unwrap();
+    let mut res: Vec<NixRDate> = Vec::new();
+    for line in readme.lines() {
+        let Some(date) = date_re.find(line) else {
+            continue;
+        };
+        let rest = line.replace(date.as_str(), " ");
+        let mut r_version = None;
+        let mut bioconductor_version = None;
+        for hit in version_re.captures_iter(&rest) {
+            let version = hit[1].to_string();
+            if version.matches('.').count() == 2 {
+                r_version.get_or_insert(version);
+            } else {
+                bioconductor_version.get_or_insert(version);
+            }
+        }
+        res.push(NixRDate {
+            date: date.as_str().to_string(),
+            r_version,
+            bioconductor_version,
+        });
+    }
+    res.sort_by(|a, b| a.date.cmp(&b.date));
+    res.dedup_by(|a, b| a.date == b.date);
+    res
+}
+
+/// The known dates just before and after a (missing) date, in order
+pub fn nearest_nixr_dates<'a>(dates: &'a [NixRDate], date: &str, n: usize) -> &'a [NixRDate] {
+    let pos = dates.partition_point(|x| x.date.as_str() < date);
+    &dates[pos.saturating_sub(n)..(pos + n).min(dates.len())]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_description() {
+        let raw = "Package: mypkg
+Title: Does things
+Version: 0.3.1
+Depends: R (>= 4.1.0),
+    methods
+Imports:
+    dplyr (>= 1.0.0),
+    Rcpp,
+    rlang
+LinkingTo: Rcpp
+Suggests: testthat
+";
+        assert_eq!(
+            parse_description(raw).unwrap(),
+            RDescription {
+                package: "mypkg".to_string(),
+                version: "0.3.1".to_string(),
+                dependencies: vec!["Rcpp".to_string(), "dplyr".to_string(), "rlang".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate_r_package_name() {
+        for name in ["ggplot2", "data.table", "Rcpp", "R6"] {
+            assert!(validate_r_package_name(name).is_ok(), "{name}");
+        }
+        for name in ["", "x", "2fast", "ends.", "my_pkg", "a\"b", "a${b}"] {
+            assert!(validate_r_package_name(name).is_err(), "{name}");
+        }
+        assert!(validate_r_name_version("mypkg_1.0-2").is_ok());
+        assert!(validate_r_name_version("mypkg").is_err());
+        assert!(validate_r_name_version("mypkg_1.0\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_renv_lock() {
+        let raw = r#"{
+            "R": {"Version": "4.3.1", "Repositories": []},
+            "Packages": {
+                "dplyr": {"Package": "dplyr", "Version": "1.1.2", "Source": "Repository"},
+                "mypkg": {"Package": "mypkg", "Version": "0.1.0", "Source": "GitHub",
+                          "RemoteUsername": "owner", "RemoteRepo": "mypkg",
+                          "RemoteRef": "main", "RemoteSha": "abc123"},
+                "utils": {"Package": "utils", "Version": "4.3.1", "Source": "Repository"}
+            }
+        }"#;
+        let imported = parse_renv_lock(raw).unwrap();
+        assert_eq!(
+            imported.r_constraint,
+            Some(("==".to_string(), "4.3.1".to_string()))
+        );
+        assert_eq!(imported.packages.len(), 2);
+        assert_eq!(
+            imported.packages[1].github_url.as_deref(),
+            Some("github:owner/mypkg/main/abc123")
+        );
+        assert!(version_satisfies(
+            "4.3.1",
+            imported.r_constraint.as_ref().unwrap()
+        ));
+        assert!(!version_satisfies(
+            "1.0.10",
+            &(">=".to_string(), "1.1.0".to_string())
+        ));
+    }
+
+    #[test]
+    fn test_parse_nixr_dates() {
+        let readme = "| date | R | Bioconductor |
+|---|---|---|
+| 2024-05-10 | 4.4.0 | 3.19 |
+| 2023-11-01 | 4.3.2 | 3.18 |
+";
+        assert_eq!(
+            parse_nixr_dates(readme),
+            vec![
+                NixRDate {
+                    date: "2023-11-01".to_string(),
+                    r_version: Some("4.3.2".to_string()),
+                    bioconductor_version: Some("3.18".to_string()),
+                },
+                NixRDate {
+                    date: "2024-05-10".to_string(),
+                    r_version: Some("4.4.0".to_string()),
+                    bioconductor_version: Some("3.19".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nearest_nixr_dates() {
+        let dates: Vec<NixRDate> = ["2023-01-01", "2023-06-01", "2023-11-01", "2024-05-10"]
+            .iter()
+            .map(|date| NixRDate {
+                date: (*date).to_string(),
+                r_version: None,
+                bioconductor_version: None,
+            })
+            .collect();
+        let nearest = |date: &str| {
+            nearest_nixr_dates(&dates, date, 1)
+                .iter()
+                .map(|x| x.date.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(nearest("2023-06-02"), vec!["2023-06-01", "2023-11-01"]);
+        assert_eq!(nearest("2022-01-01"), vec!["2023-01-01"]);
+        assert_eq!(nearest("2025-01-01"), vec!["2024-05-10"]);
+    }
+}
@@ -0,0 +1,98 @@
+{
+  description = "Anysnake2 generated flake";
+  inputs = rec {
+    flake-utils = {
+      url = "github:numtide/flake-utils/11707dc2f618dd54ca8739b309ec4fc024de578b";
+    };
+    nixpkgs = {
+      url = "github:NixOS/nixpkgs/24.11";
+    };
+    hello = {
+      url = "git+https://example.com/hello?ref=main&rev=0123456789abcdef&dir=sub dir";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+  };
+  outputs = {
+    self,
+    flake-utils,
+    nixpkgs,
+    hello,
+  }@flake_inputs:
+  flake-utils.lib.eachDefaultSystem (system: let
+    pkgs = import nixpkgs {
+      inherit system overlays;
+      config = {
+        allowUnfree = true;
+        permittedInsecurePackages = [ "openssl-1.1.1w" ];
+        cudaSupport = false;
+      };
+    };
+    R_tracked = null;
+    overlays = [
+      (final: prev: {
+        pkgconfig = final.pkg-config;
+      })
+    ];
+    python_envs = { };
+    _args = with pkgs; {
+      name = "anysnake2_container";
+      inherit python_envs;
+      script = let
+        bash = let
+          s = builtins.tryEval (pkgs.bashInteractive_5 or pkgs.bashInteractive);
+        in
+          if s.success
+          then s.value
+          else pkgs.bashInteractive;
+      in
+      ''
+        ${coreutils}
+        ${bash}
+        ${cacert}
+        ${hello.packages.${system}.default}
+      '';
+    };
+    helpers = import ./functions.nix {
+      inherit pkgs;
+    };
+  in
+  rec {
+    packages = {
+      default = (helpers.buildSymlinkImage _args).derivation;
+      oci_image = helpers.buildOCIimage _args;
+      flake_inputs_for_gc_root = pkgs.stdenv.mkDerivation {
+        pname = "anysnake2-flake-inputs";
+        version = "0.1";
+        unpackPhase = ":";
+        installPhase = ":";
+        buildPhase = let
+          str_inputs =
+            builtins.concatStringsSep "\n"
+            (map (key: "ln -s ${flake_inputs.${key}} ${key}") (builtins.attrNames flake_inputs));
+        in
+          ''
+            mkdir $out -p
+            cd $out/
+          ''
+          + str_inputs;
+      };
+    };
+    devShell = pkgs.stdenv.mkDerivation {
+      name = "anysnake2-devshell";
+      shellHook = ''
+        export PATH=${packages.default}/rootfs/bin:$PATH;
+        if test -f "develop_python_path.bash"; then
+          source "develop_python_path.bash"
+        fi
+      ''
+      + (
+        if R_tracked != null
+        then ''
+          export R_LIBS_SITE=${R_tracked}/lib/R/library/
+        ''
+        else ""
+      );
+      nativeBuildInputs = with pkgs; [ git ];
+    };
+  });
+}
\ No newline at end of file
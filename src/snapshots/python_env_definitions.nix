@@ -0,0 +1,113 @@
+let
+  fix_resolve_build_systems_old = final: prev: {
+    resolveBuildSystem = arg: prev.resolveBuildSystem (arg
+    // (
+      if arg.setuptools or null != null
+      then {wheel = [];}
+      else {}
+    ));
+  };
+  interpreter_old = pkgs.python311;
+  local_anysnake_overrides_old = final: prev: {
+    rpy2 = prev.rpy2.overrideAttrs (old: {
+      postBuild = ''
+                          WHEEL_NAME="$(find -name '*.whl')"
+                          if [ "$(echo "$WHEEL_NAME" | wc -l)" -eq 1 ] && [ -n "$WHEEL_NAME" ]; then
+                              # exactly one line, assume its a wheel.
+                              mkdir temp
+
+                              WHEEL_NAME=$(realpath $WHEEL_NAME) 
+                              ${pkgs.unzip}/bin/unzip "$WHEEL_NAME" -d temp
+                              if [[ -f temp/rpy2/rinterface_lib/__init__.py ]]; then 
+                                  echo -e "\nimport os
+      os.environ['R_LIBS_SITE'] = '${R_tracked}/lib/R/library'" >> temp/rpy2/rinterface_lib/__init__.py
+                                  cd temp && ${pkgs.zip}/bin/zip -m -r "$WHEEL_NAME" *
+                                  cd ..
+                                  rmdir temp
+                              else
+                                  # probably a newer rpy2 which ain't got the file.
+                                  rm temp -rf
+                              fi
+                          else
+                              # not a wheel
+                              if [[ ! -f rpy2/rinterface_lib/__init__.py ]]; then
+                                  echo 'missing file'
+                                  exit 1
+                              fi
+                              echo -e "\nimport os
+      os.environ['R_LIBS_SITE'] = '${R_tracked}/lib/R/library'" >> rpy2/rinterface_lib/__init__.py
+                          fi
+                      '';
+    });
+    rpy2-rinterface = prev.rpy2-rinterface.overrideAttrs (old: {
+      postBuild = ''
+                          WHEEL_NAME="$(find -name '*.whl')"
+                          if [ "$(echo "$WHEEL_NAME" | wc -l)" -eq 1 ] && [ -n "$WHEEL_NAME" ]; then
+                              # exactly one line, assume its a wheel.
+                              mkdir temp
+
+                              WHEEL_NAME=$(realpath $WHEEL_NAME) 
+                              ${pkgs.unzip}/bin/unzip "$WHEEL_NAME" -d temp
+                              if [[ -f temp/rpy2/rinterface_lib/__init__.py ]]; then 
+                                  echo -e "\nimport os
+      os.environ['R_LIBS_SITE'] = '${R_tracked}/lib/R/library'" >> temp/rpy2/rinterface_lib/__init__.py
+                                  cd temp && ${pkgs.zip}/bin/zip -m -r "$WHEEL_NAME" *
+                                  cd ..
+                                  rmdir temp
+                              else
+                                  # probably a newer rpy2 which ain't got the file.
+                                  rm temp -rf
+                              fi
+                          else
+                              # not a wheel
+                              if [[ ! -f rpy2/rinterface_lib/__init__.py ]]; then
+                                  echo 'missing file'
+                                  exit 1
+                              fi
+                              echo -e "\nimport os
+      os.environ['R_LIBS_SITE'] = '${R_tracked}/lib/R/library'" >> rpy2/rinterface_lib/__init__.py
+                          fi
+                      '';
+    });
+    scanpy = prev.scanpy.overrideAttrs (old: {
+      nativeBuildInputs = old.nativeBuildInputs ++ (final.resolveBuildSystem {
+        setuptools = [ ];
+      });
+    });
+  };
+  local_user_overrides_old = final: prev: {
+    scanpy = prev.scanpy.overrideAttrs (old: {
+      postPatch = ''
+        echo hello
+      '';
+    });
+  };
+  overlay_old = workspace_old.mkPyprojectOverlay {
+    sourcePreference = "wheel";
+  };
+  pyprojectOverrides_old = [
+    fix_resolve_build_systems_old
+    (uv2nix_override_collection.overrides pkgs)
+    local_anysnake_overrides_old
+    local_user_overrides_old
+  ];
+  pythonSet_old = (pkgs.callPackage pyproject-nix.build.packages {
+    python = interpreter_old;
+  }).overrideScope (pkgs.lib.composeManyExtensions ([
+    pyproject-build-systems.overlays.default
+    overlay_old
+    source_preference_overrides_old
+  ] ++ pyprojectOverrides_old));
+  source_preference_overrides_old = final: prev: let
+    alternative = (workspace_old.mkPyprojectOverlay {
+      sourcePreference = "sdist";
+    }) final prev;
+  in
+  {
+    scanpy = alternative.scanpy;
+  };
+  workspace_old = uv2nix.lib.workspace.loadWorkspace {
+    workspaceRoot = ./uv_rewritten/old;
+  };
+in
+pythonSet_old
\ No newline at end of file
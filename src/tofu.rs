@@ -1,5 +1,9 @@
 use anyhow::{anyhow, bail, Context, Result};
-use std::{collections::HashMap, path::PathBuf, process::Command};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    process::Command,
+};
 use toml_edit::value;
 
 #[allow(unused_imports)]
@@ -7,6 +11,8 @@ use log::{debug, error, info, warn};
 
 use crate::{
     config::{self, SafePythonName, TofuAnysnake2, TofuConfigToml, TofuDevShell, TofuVCSorDev},
+    nix_expr::{checked_identifier, Expr},
+    r_parsing,
     vcs::{self, BranchOrTag, ParsedVCS, TofuVCS},
 };
 use anysnake2::util::{change_toml_file, get_proxy_req, TomlUpdates};
@@ -21,9 +27,10 @@ trait Tofu<A> {
     fn tofu(self, updates: &mut TomlUpdates) -> Result<A>;
 }
 
-impl Tofu<config::TofuConfigToml> for config::ConfigToml {
+impl config::ConfigToml {
+    /// `flake_dir` caches the nixR dates
     #[allow(clippy::too_many_lines)]
-    fn tofu(self, updates: &mut TomlUpdates) -> Result<config::TofuConfigToml> {
+    fn tofu(self, updates: &mut TomlUpdates, flake_dir: &Path) -> Result<config::TofuConfigToml> {
         let converted_clone_regexps = match self.clone_options.clone_regexps.as_ref() {
             Some(cr) => Some(clone_regex_strings_to_regex(cr)?),
             None => None,
@@ -88,7 +95,10 @@ impl Tofu<config::TofuConfigToml> for config::ConfigToml {
         )?; //todo: only tofu newest nixpkgs release.. Doesn't this do this already?
         anysnake2::define_outside_nipkgs_url(outside_nixpkgs.to_nix_string());
 
-        Ok(config::TofuConfigToml {
+        let mut rust = self.rust;
+        let rust_tools = rust.as_mut().and_then(|rust| rust.tools.take());
+
+        let mut res = config::TofuConfigToml {
             //anysnake2_toml_path: self.anysnake2_toml_path,
             anysnake2: {
                 config::TofuAnysnake2 {
@@ -142,20 +152,192 @@ impl Tofu<config::TofuConfigToml> for config::ConfigToml {
             },
             clone_options: self.clone_options,
             cmd: self.cmd,
-            rust: self
-                .rust
-                .tofu_to_newest(&["rust"], updates, "github:oxalica/rust-overlay")?,
+            rust: rust.tofu_to_newest(&["rust"], updates, "github:oxalica/rust-overlay")?,
             python: python.tofu(updates)?,
             container: self.container,
             flakes: self.flakes.tofu(updates)?,
             dev_shell: self.dev_shell.tofu(updates)?,
-            r: self
-                .r
-                .tofu_to_newest(&["R"], updates, "github:TyberiusPrime/nixR")?,
-        })
+            r: tofu_r(self.r, &["R"], updates, NIXR_DEFAULT_URL, flake_dir)?,
+            julia: self
+                .julia
+                .tofu_to_newest(&["julia"], updates, JULIA_REGISTRY_DEFAULT_URL)?,
+            node: self.node,
+            packages: self.packages,
+        };
+        if let (Some(rust), Some(rust_tools)) = (res.rust.as_mut(), rust_tools) {
+            rust.tools = tofu_rust_tools(rust_tools, &res.nixpkgs.url, updates)?;
+        }
+        Ok(res)
     }
 }
 
+/// Resolve crate versions, and discover (and write back) the source and cargo hashes.
+/// The hashes are rediscovered when the version / rev they were recorded for changed.
+fn tofu_rust_tools(
+    tools: HashMap<String, config::RustTool>,
+    nixpkgs: &TofuVCS,
+    updates: &mut TomlUpdates,
+) -> Result<BTreeMap<String, config::TofuRustTool>> {
+    let mut res = BTreeMap::new();
+    for (name, tool) in tools {
+        let toml_path = ["rust", "tools", name.as_str()];
+        let mut changed = tool.cargo_hash.is_none()
+            || matches!(&tool.source, config::RustToolSource::Crate { version, hash } if version.is_empty() || hash.is_none());
+        let is_stale = |pinned: &str| {
+            let stale = tool
+                .hashes_for
+                .as_ref()
+                .is_some_and(|hashes_for| hashes_for != pinned);
+            if stale {
+                info!("Rust tool {name} changed to {pinned}, rediscovering its hashes");
+            }
+            stale
+        };
+        let mut cargo_hash = tool.cargo_hash;
+        let (source, version, pinned, src) = match tool.source {
+            config::RustToolSource::Crate { version, hash } => {
+                let version = if version.is_empty() {
+                    newest_crate_version(&name)?
+                } else {
+                    version
+                };
+                let stale = is_stale(&version);
+                if stale {
+                    cargo_hash = None;
+                }
+                let hash = match hash {
+                    Some(hash) if !stale => hash,
+                    _ => discover_crate_hash(nixpkgs, &name, &version)?,
+                };
+                let src = crate::flake_writer::fetch_crate(&name, &version, Expr::str(&hash));
+                (
+                    config::TofuRustToolSource::Crate {
+                        version: version.clone(),
+                        hash,
+                    },
+                    version.clone(),
+                    version,
+                    src,
+                )
+            }
+            config::RustToolSource::Vcs(url) => {
+                let url = tofu_repo_to_newest(
+                    &[toml_path.as_slice(), &["url"]].concat(),
+                    updates,
+                    Some(url),
+                    "",
+                )?;
+                let (_, rev, _) = url.get_url_rev_branch();
+                let version = rev.chars().take(12).collect::<String>();
+                let pinned = rev.to_string();
+                if is_stale(&pinned) {
+                    cargo_hash = None;
+                }
+                let src = if cargo_hash.is_none() {
+                    crate::flake_writer::vcs_to_nix_store(
+                        &url,
+                        &HashMap::new(),
+                        &mut HashMap::new(),
+                    )?
+                    .1
+                } else {
+                    Expr::Null // not needed
+                };
+                (config::TofuRustToolSource::Vcs(url), version, pinned, src)
+            }
+        };
+        changed |= tool.hashes_for.as_deref() != Some(pinned.as_str());
+        let cargo_hash = match cargo_hash {
+            Some(cargo_hash) => cargo_hash,
+            None => discover_cargo_hash(nixpkgs, &name, &version, src)?,
+        };
+        // write back everything, so that a 'name = "version"' becomes a table
+        if changed {
+            let mut out = toml_edit::Table::new().into_inline_table();
+            match &source {
+                config::TofuRustToolSource::Crate { version, hash } => {
+                    out.insert("version", version.into());
+                    out.insert("hash", hash.into());
+                }
+                config::TofuRustToolSource::Vcs(url) => {
+                    out.insert("url", url.to_string().into());
+                }
+            }
+            out.insert("cargo_hash", cargo_hash.as_str().into());
+            out.insert("hashes_for", pinned.as_str().into());
+            updates.push((
+                toml_path.iter().map(ToString::to_string).collect(),
+                value(out),
+            ));
+        }
+        res.insert(name, config::TofuRustTool { source, cargo_hash });
+    }
+    Ok(res)
+}
+
+fn discover_crate_hash(nixpkgs: &TofuVCS, name: &str, version: &str) -> Result<String> {
+    info!("Discovering source hash for rust tool {name} {version}");
+    discover_nix_hash(
+        nixpkgs,
+        &crate::flake_writer::fetch_crate(name, version, Expr::var("pkgs.lib.fakeHash"))
+            .to_string(),
+    )
+    .with_context(|| format!("Failed to fetch crate {name} {version}"))
+}
+
+fn discover_cargo_hash(nixpkgs: &TofuVCS, name: &str, version: &str, src: Expr) -> Result<String> {
+    info!("Discovering cargo hash for rust tool {name}");
+    discover_nix_hash(
+        nixpkgs,
+        &crate::flake_writer::build_rust_package(
+            name,
+            version,
+            src,
+            Expr::var("pkgs.lib.fakeHash"),
+        )
+        .select(["cargoDeps"])
+        .to_string(),
+    )
+    .with_context(|| format!("Failed to discover cargo hash for {name}"))
+}
+
+fn newest_crate_version(name: &str) -> Result<String> {
+    let url = format!("https://crates.io/api/v1/crates/{name}");
+    let json = get_proxy_req()?
+        .get(&url)
+        .header(
+            "User-Agent",
+            "anysnake2 (https://github.com/TyberiusPrime/anysnake2)",
+        )
+        .call()
+        .with_context(|| format!("Failed to query {url}"))?
+        .body_mut()
+        .read_to_string()?;
+    let parsed: serde_json::Value = serde_json::from_str(&json)?;
+    Ok(parsed["crate"]["max_stable_version"]
+        .as_str()
+        .with_context(|| format!("No max_stable_version for {name} on crates.io"))?
+        .to_string())
+}
+
+/// Build a fixed output derivation with a fake hash (using our nixpkgs) and return the actual hash
+fn discover_nix_hash(nixpkgs: &TofuVCS, expr: &str) -> Result<String> {
+    let full_expr = format!(
+        "let pkgs = import (builtins.getFlake \"{}\") {{ system = builtins.currentSystem; }}; in {expr}",
+        nixpkgs.to_nix_string()
+    );
+    let output = Command::new("nix")
+        .args(["build", "--impure", "--no-link", "--expr", &full_expr])
+        .output()
+        .context("nix build call failed")?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let sha_re = regex::Regex::new(r"got:\s+(sha256-[A-Za-z0-9+/=]+)").unwrap();
+    let hit = sha_re.captures(&stderr).with_context(|| {
+        format!("Expected a hash mismatch from nix build. stderr was: {stderr}")
+    })?;
+    Ok(hit[1].to_string())
+}
+
 fn add_rpy2_if_missing(python: &mut Option<config::Python>, _updates: &mut TomlUpdates) {
     // currently this is not feeding back into the anysnake2.toml.
     // One could argue it should, but then we loose the ability to easily patch this on newer
@@ -172,6 +354,10 @@ fn add_rpy2_if_missing(python: &mut Option<config::Python>, _updates: &mut TomlU
                 anysnake_override_attrs: None,
                 patch_before_lock: None,
                 build_systems: None,
+                ecosystem_date: None,
+                prefer: None,
+                patches: Vec::new(),
+                tag_regex: None,
             };
             python.packages.insert(SafePythonName::new("rpy2"), def);
         }
@@ -359,12 +545,24 @@ impl TofuToTag<config::TofuNixPkgs> for Option<config::NixPkgs> {
             tag_regex,
         )?;
 
+        let nixpkgs_config = inner_self.config.unwrap_or_default();
+        for (key, replacement) in [
+            ("allowUnfree", "allow_unfree"),
+            ("permittedInsecurePackages", "permitted_insecure_packages"),
+        ] {
+            if nixpkgs_config.contains_key(key) {
+                bail!("[nixpkgs.config] may not set {key} - use [nixpkgs] {replacement} instead");
+            }
+        }
+
         let out = config::TofuNixPkgs {
             url: url_and_rev,
             packages: inner_self.packages.unwrap_or_default(),
             allow_unfree: inner_self.allow_unfree,
             permitted_insecure_packages: inner_self.permitted_insecure_packages,
             overlay: inner_self.overlay,
+            config: nixpkgs_config,
+            overlays: inner_self.overlays.unwrap_or_default(),
         };
         Ok(out)
     }
@@ -430,81 +628,353 @@ impl TofuToNewest<Option<config::TofuRust>> for Option<config::Rust> {
             None => None,
             Some(rust) => {
                 let url = tofu_repo_to_newest(&url_toml_name, updates, rust.url, default_url)?;
+                let channel = rust.channel.unwrap_or(config::RustChannel::Stable);
+                let toolchain_file = match (rust.version.as_deref(), rust.toolchain_file) {
+                    (Some("file") | None, Some(toolchain_file)) => Some(toolchain_file),
+                    (Some("file"), None) => Some(
+                        ["rust-toolchain.toml", "rust-toolchain"]
+                            .iter()
+                            .find(|x| anysnake2::config_relative_path(x).exists())
+                            .unwrap_or(&"rust-toolchain.toml")
+                            .to_string(),
+                    ),
+                    (Some(_), Some(_)) => {
+                        bail!("[rust] toolchain_file can not be combined with a version (other than 'file')")
+                    }
+                    (_, None) => None,
+                };
+                if toolchain_file.is_some() && rust.channel.is_some() {
+                    bail!("[rust] channel can not be combined with a toolchain file - set the channel in the file");
+                }
+                if toolchain_file.is_some() && rust.profile.is_some() {
+                    bail!("[rust] profile can not be combined with a toolchain file - set the profile in the file");
+                }
                 #[allow(clippy::single_match_else)]
                 let version = match rust.version {
-                    Some(v) => v,
+                    _ if toolchain_file.is_some() => "file".to_string(),
+                    Some(v) => {
+                        if channel != config::RustChannel::Stable {
+                            v.parse::<jiff::civil::Date>().with_context(|| {
+                                format!(
+                                    "[rust] version for the {} channel must be a date (yyyy-mm-dd), was {v}",
+                                    channel.as_str()
+                                )
+                            })?;
+                        }
+                        v
+                    }
                     None => {
                         debug!("Tofu for rust");
-                        let rust_flake_contents = std::process::Command::new("nix")
-                            .args(["flake", "show", "--json", &url.to_nix_string()])
-                            .output()
-                            .with_context(|| format!("nix flake show --json {url} failed"))?;
-                        let rust_flake_contents = std::str::from_utf8(&rust_flake_contents.stdout);
-                        let json: serde_json::Value = serde_json::from_str(rust_flake_contents?)
-                            .context("nix flake show --json wasn't json")?;
-                        let rust = json["packages"]["x86_64-linux"]["default"]["name"]
-                            .as_str()
-                            .context("Could not find default version in flake show")?;
-                        let actual_version = rust.split('-').last().context("rust version naming scheme changed, expected something like 'rust-default-1.81.0?'")?;
-                        debug!("Found version: {actual_version}");
-                        actual_version.to_string()
+                        let version = newest_rust_version(&url, channel)?;
+                        let mut version_toml_name: Vec<String> =
+                            toml_name.iter().map(ToString::to_string).collect();
+                        version_toml_name.push("version".to_string());
+                        updates.push((version_toml_name, value(version.clone())));
+                        version
                     }
                 };
-                Some(config::TofuRust { version, url })
+                Some(config::TofuRust {
+                    version,
+                    url,
+                    toolchain_file,
+                    channel,
+                    profile: rust.profile.unwrap_or(config::RustProfile::Minimal),
+                    components: rust.components,
+                    targets: rust.targets,
+                    tools: BTreeMap::new(), // filled in by tofu_rust_tools, they need the nixpkgs
+                })
             }
         })
     }
 }
 
-impl TofuToNewest<Option<config::TofuR>> for Option<config::R> {
+/// The nix system we're running on, e.g. x86_64-linux or aarch64-linux
+fn nix_host_system() -> Result<String> {
+    let output = Command::new("nix")
+        .args([
+            "eval",
+            "--impure",
+            "--raw",
+            "--expr",
+            "builtins.currentSystem",
+        ])
+        .output()
+        .context("nix eval builtins.currentSystem failed")?;
+    if !output.status.success() {
+        bail!(
+            "nix eval builtins.currentSystem failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// The newest version (stable) or date (beta/nightly) a rust-overlay revision offers
+fn newest_rust_version(url: &TofuVCS, channel: config::RustChannel) -> Result<String> {
+    let rust_flake_contents = std::process::Command::new("nix")
+        .args(["flake", "show", "--json", &url.to_nix_string()])
+        .output()
+        .with_context(|| format!("nix flake show --json {url} failed"))?;
+    let rust_flake_contents = std::str::from_utf8(&rust_flake_contents.stdout);
+    let json: serde_json::Value =
+        serde_json::from_str(rust_flake_contents?).context("nix flake show --json wasn't json")?;
+    let package = match channel {
+        config::RustChannel::Stable => "default",
+        config::RustChannel::Beta => "rust-beta",
+        config::RustChannel::Nightly => "rust-nightly",
+    };
+    let system = nix_host_system()?;
+    let rust = json["packages"][system.as_str()][package]["name"]
+        .as_str()
+        .with_context(|| {
+            format!("Could not find {package} version in flake show. Set [rust] version manually")
+        })?;
+    debug!("Found rust package: {rust}");
+    if channel == config::RustChannel::Stable {
+        let actual_version = rust.split('-').next_back().context(
+            "rust version naming scheme changed, expected something like 'rust-default-1.81.0?'",
+        )?;
+        Ok(actual_version.to_string())
+    } else {
+        // e.g. 'rust-default-1.85.0-nightly-2024-12-01'
+        let date_re = regex::Regex::new(r"(\d{4}-\d{2}-\d{2})$").unwrap();
+        let date = date_re.captures(rust).with_context(|| {
+            format!("rust version naming scheme changed, expected a date at the end of '{rust}'")
+        })?;
+        Ok(date[1].to_string())
+    }
+}
+
+fn tofu_r(
+    r: Option<config::R>,
+    toml_name: &[&str],
+    updates: &mut TomlUpdates,
+    default_url: &str,
+    flake_dir: &Path,
+) -> Result<Option<config::TofuR>> {
+    let mut url_toml_name = toml_name
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>();
+    url_toml_name.push("url".to_string());
+    let ref_url_toml_name: Vec<&str> = url_toml_name.iter().map(String::as_str).collect();
+    Ok(match r {
+        None => None,
+        Some(inner_self) => {
+            validate_r_names(&inner_self)?;
+            let url =
+                tofu_repo_to_newest(&ref_url_toml_name, updates, inner_self.url, default_url)?;
+            #[allow(clippy::single_match_else)]
+            let date = match inner_self.date {
+                Some(date) => {
+                    validate_nixr_date(&url, &date, flake_dir)?;
+                    date
+                }
+                None => {
+                    let date = find_newest_nixr_date(&url, flake_dir)?;
+                    let mut date_toml_name = toml_name
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<String>>();
+                    date_toml_name.push("date".to_string());
+                    updates.push((date_toml_name, value(date.clone())));
+                    //time to findout
+                    date
+                }
+            };
+
+            let mut vcs_packages = BTreeMap::new();
+            for (name, spec) in inner_self.vcs_packages.unwrap_or_default() {
+                let pkg_url = tofu_repo_to_newest(
+                    &[toml_name, &["vcs_packages", name.as_str(), "url"]].concat(),
+                    updates,
+                    Some(spec.url),
+                    "",
+                )
+                .with_context(|| format!("Tofu R package failed: {name}"))?;
+                vcs_packages.insert(
+                    name,
+                    config::TofuRVcsPackage {
+                        url: pkg_url,
+                        editable_path: spec.editable,
+                    },
+                );
+            }
+
+            Some(config::TofuR {
+                date,
+                packages: inner_self.packages,
+                url,
+                override_attrs: inner_self.override_attrs,
+                dependency_overrides: inner_self.dependency_overrides,
+                additional_packages: inner_self.additional_packages,
+                use_inside_nix_pkgs: inner_self.use_inside_nix_pkgs,
+                vcs_packages,
+            })
+        }
+    })
+}
+
+/// R package names end up in nix attribute sets and nixR's package lists
+fn validate_r_names(r: &config::R) -> Result<()> {
+    for name in &r.packages {
+        r_parsing::validate_r_package_name(name).context("Invalid entry in R.packages")?;
+    }
+    for (key, entries) in [
+        ("override_attrs", &r.override_attrs),
+        ("dependency_overrides", &r.dependency_overrides),
+    ] {
+        for name in entries.iter().flat_map(HashMap::keys) {
+            r_parsing::validate_r_package_name(name)
+                .with_context(|| format!("Invalid key in R.{key}"))?;
+        }
+    }
+    for key in r.additional_packages.iter().flat_map(HashMap::keys) {
+        r_parsing::validate_r_name_version(key).context("Invalid key in R.additional_packages")?;
+    }
+    for name in r.vcs_packages.iter().flat_map(HashMap::keys) {
+        r_parsing::validate_r_package_name(name).context("Invalid key in R.vcs_packages")?;
+    }
+    Ok(())
+}
+
+/// The General registry, augmented with the nix hashes nixpkgs' julia.withPackages needs
+const JULIA_REGISTRY_DEFAULT_URL: &str = "github:CodeDownIO/General";
+
+impl TofuToNewest<Option<config::TofuJulia>> for Option<config::Julia> {
     fn tofu_to_newest(
         self,
         toml_name: &[&str],
         updates: &mut TomlUpdates,
         default_url: &str,
-    ) -> Result<Option<config::TofuR>> {
-        let mut url_toml_name = toml_name
-            .iter()
-            .map(ToString::to_string)
-            .collect::<Vec<String>>();
-        url_toml_name.push("url".to_string());
-        let ref_url_toml_name: Vec<&str> = url_toml_name.iter().map(String::as_str).collect();
-        Ok(match self {
-            None => None,
-            Some(inner_self) => {
-                let url =
-                    tofu_repo_to_newest(&ref_url_toml_name, updates, inner_self.url, default_url)?;
-                #[allow(clippy::single_match_else)]
-                let date = match inner_self.date {
-                    Some(date) => date,
-                    None => {
-                        let date = find_newest_nixr_date(&url)?;
-                        let mut date_toml_name = toml_name
-                            .iter()
-                            .map(ToString::to_string)
-                            .collect::<Vec<String>>();
-                        date_toml_name.push("date".to_string());
-                        updates.push((date_toml_name, value(date.to_string())));
-                        //time to findout
-                        date
-                    }
+    ) -> Result<Option<config::TofuJulia>> {
+        let Some(inner_self) = self else {
+            return Ok(None);
+        };
+        if !regex::Regex::new(r"^\d+\.\d+$")
+            .unwrap()
+            .is_match(&inner_self.version)
+        {
+            bail!(
+                "julia.version must be major.minor, as in \"1.10\", was {:?}",
+                inner_self.version
+            );
+        }
+        let registry_toml_name = [toml_name, &["registry"]].concat();
+        let mut registry = inner_self
+            .registry
+            .unwrap_or_else(|| default_url.try_into().expect("invalid default url"));
+        // the ecosystem_date changed since the rev was resolved -> resolve again
+        if let (Some(date), Some(registry_date), ParsedVCS::GitHub { rev, .. }) = (
+            &inner_self.ecosystem_date,
+            &inner_self.registry_date,
+            &mut registry,
+        ) {
+            if date != registry_date && rev.is_some() {
+                info!("julia.ecosystem_date changed from {registry_date} to {date}, resolving the registry rev again");
+                *rev = None;
+            }
+        }
+        let registry = match (&inner_self.ecosystem_date, registry) {
+            (
+                Some(date),
+                ParsedVCS::GitHub {
+                    owner,
+                    repo,
+                    branch,
+                    rev: None,
+                },
+            ) => {
+                let parsed: jiff::civil::Date = date.parse().with_context(|| {
+                    format!("julia.ecosystem_date {date:?} is not a valid yyyy-mm-dd date")
+                })?;
+                let input = ParsedVCS::GitHub {
+                    owner: owner.clone(),
+                    repo: repo.clone(),
+                    branch: branch.clone(),
+                    rev: None,
                 };
-
-                Some(config::TofuR {
-                    date,
-                    packages: inner_self.packages,
-                    url,
-                    override_attrs: inner_self.override_attrs,
-                    dependency_overrides: inner_self.dependency_overrides,
-                    additional_packages: inner_self.additional_packages,
-                    use_inside_nix_pkgs: inner_self.use_inside_nix_pkgs,
-                })
+                let branch = match branch {
+                    Some(branch) => branch,
+                    None => input.discover_main_branch()?,
+                };
+                let rev = github_commit_before(&owner, &repo, &branch, parsed)?;
+                let registry = TofuVCS::GitHub {
+                    owner,
+                    repo,
+                    branch,
+                    rev,
+                };
+                updates.push((
+                    registry_toml_name.iter().map(ToString::to_string).collect(),
+                    value(registry.to_string_including_username()),
+                ));
+                updates.push((
+                    [toml_name, &["registry_date"]]
+                        .concat()
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect(),
+                    value(date.clone()),
+                ));
+                registry
             }
-        })
+            (Some(_), registry @ ParsedVCS::GitHub { rev: Some(_), .. }) | (None, registry) => {
+                tofu_repo_to_newest(&registry_toml_name, updates, Some(registry), default_url)?
+            }
+            (Some(_), _) => {
+                bail!("julia.ecosystem_date needs a github: registry url (or one with a rev)")
+            }
+        };
+        Ok(Some(config::TofuJulia {
+            version: inner_self.version,
+            packages: inner_self.packages,
+            registry,
+        }))
     }
 }
 
-fn find_newest_nixr_date(url: &TofuVCS) -> Result<String> {
+/// The newest commit on a github branch at the end of the given day
+fn github_commit_before(
+    owner: &str,
+    repo: &str,
+    branch: &str,
+    date: jiff::civil::Date,
+) -> Result<String> {
+    let url = format!(
+        "https://api.github.com/repos/{owner}/{repo}/commits?sha={branch}&until={date}T23:59:59Z&per_page=1"
+    );
+    debug!("querying {url}");
+    let json = get_proxy_req()?
+        .get(&url)
+        .header(
+            "User-Agent",
+            "anysnake2 (https://github.com/TyberiusPrime/anysnake2)",
+        )
+        .call()
+        .with_context(|| format!("Failed to query {url}"))?
+        .body_mut()
+        .read_to_string()?;
+    let parsed: serde_json::Value = serde_json::from_str(&json)?;
+    Ok(parsed[0]["sha"]
+        .as_str()
+        .with_context(|| format!("No commit on {owner}/{repo}/{branch} before {date}"))?
+        .to_string())
+}
+
+pub const NIXR_DEFAULT_URL: &str = "github:TyberiusPrime/nixR";
+
+fn find_newest_nixr_date(url: &TofuVCS, flake_dir: &Path) -> Result<String> {
+    let all_dates = fetch_nixr_dates(url, flake_dir)?;
+    let last_date = all_dates
+        .last()
+        .context("Could not find dates in nixR's generated/readme.md")?;
+    Ok(last_date.date.clone())
+}
+
+/// The dates (and R/Bioconductor versions) a nixR revision knows about.
+/// Cached in `flake_dir/nixr_dates`
+pub fn fetch_nixr_dates(url: &TofuVCS, flake_dir: &Path) -> Result<Vec<r_parsing::NixRDate>> {
     match url {
         TofuVCS::GitHub {
             owner,
@@ -515,21 +985,27 @@ fn find_newest_nixr_date(url: &TofuVCS) -> Result<String> {
             let url = format!(
                 "https://raw.githubusercontent.com/{owner}/{repo}/{rev}/generated/readme.md"
             );
-            let text = get_proxy_req()?
-                .get(&url)
-                .call()?
-                .body_mut()
-                .read_to_string()?;
-            let date_re = regex::Regex::new(r"(\d{4}-\d{2}-\d{2})")?;
-            let mut all_dates = date_re
-                .find_iter(&text)
-                .map(|x| x.as_str())
-                .collect::<Vec<_>>();
-            all_dates.sort_unstable();
-            let last_date = all_dates
-                .last()
-                .with_context(|| format!("Could not find dates on {url}"))?;
-            Ok((*last_date).to_string())
+            // a given revision's readme never changes.
+            let cache_dir = flake_dir.join("nixr_dates");
+            let cache_file = cache_dir.join(format!("{rev}.md"));
+            let text = if let Ok(text) = ex::fs::read_to_string(&cache_file) {
+                text
+            } else {
+                let text = get_proxy_req()?
+                    .get(&url)
+                    .call()?
+                    .body_mut()
+                    .read_to_string()?;
+                if ex::fs::create_dir_all(&cache_dir).is_ok() {
+                    ex::fs::write(&cache_file, &text).ok();
+                }
+                text
+            };
+            let all_dates = r_parsing::parse_nixr_dates(&text);
+            if all_dates.is_empty() {
+                bail!("Could not find dates on {url}");
+            }
+            Ok(all_dates)
         }
         _ => {
             bail!("Only know how to determite newest date for R from nixR github, not from other VCS. Add it manually, please");
@@ -537,6 +1013,44 @@ fn find_newest_nixr_date(url: &TofuVCS) -> Result<String> {
     }
 }
 
+/// Fail early on an [R].date that nixR does not know, instead of deep inside nix evaluation
+fn validate_nixr_date(url: &TofuVCS, date: &str, flake_dir: &Path) -> Result<()> {
+    if !matches!(url, TofuVCS::GitHub { .. }) {
+        return Ok(());
+    }
+    let all_dates = match fetch_nixr_dates(url, flake_dir) {
+        Ok(all_dates) => all_dates,
+        Err(e) => {
+            warn!("Could not verify R date {date}: {e}");
+            return Ok(());
+        }
+    };
+    if all_dates.iter().any(|x| x.date == date) {
+        return Ok(());
+    }
+    let suggestions = r_parsing::nearest_nixr_dates(&all_dates, date, 2)
+        .iter()
+        .map(|x| {
+            format!(
+                "\t{} (R {}, Bioconductor {})",
+                x.date,
+                x.r_version.as_deref().unwrap_or("?"),
+                x.bioconductor_version.as_deref().unwrap_or("?")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    bail!("[R].date {date} is not available in nixR. Nearest valid dates:\n{suggestions}\n(see 'anysnake2 r dates' for all of them)");
+}
+
+/// The nixR url from [R], or the newest nixR if there is no [R] section yet.
+pub fn tofu_nixr_url(r: Option<&config::TofuR>, updates: &mut TomlUpdates) -> Result<TofuVCS> {
+    match r {
+        Some(r) => Ok(r.url.clone()),
+        None => tofu_repo_to_newest(&["R", "url"], updates, None, NIXR_DEFAULT_URL),
+    }
+}
+
 impl Tofu<HashMap<String, config::TofuFlake>> for Option<HashMap<String, config::Flake>> {
     fn tofu(self, updates: &mut TomlUpdates) -> Result<HashMap<String, config::TofuFlake>> {
         match self {
@@ -544,11 +1058,28 @@ impl Tofu<HashMap<String, config::TofuFlake>> for Option<HashMap<String, config:
             Some(flakes) => flakes
                 .into_iter()
                 .map(|(key, value)| {
-                    let tofued = tofu_repo_to_newest(
-                        &["flakes", &key, "url"],
+                    checked_identifier("flake name", &key)?;
+                    for follows in value.follows.iter().flatten() {
+                        checked_identifier(&format!("flakes.{key}.follows entry"), follows)?;
+                    }
+                    let (url_key, url) = match (value.url, value.path) {
+                        (Some(url), None) => ("url", url),
+                        (None, Some(path)) => {
+                            let path = if path.starts_with("path:") {
+                                path
+                            } else {
+                                format!("path:{path}")
+                            };
+                            ("path", ParsedVCS::try_from(path.as_str())?)
+                        }
+                        _ => bail!("[flakes.{key}] needs either a url or a path"),
+                    };
+                    let toml_name = ["flakes", &key, url_key];
+                    let tofued = tofu_repo_to_tag_or_newest(
+                        &toml_name,
                         updates,
-                        Some(value.url),
-                        "",
+                        url,
+                        value.tag_regex.as_deref(),
                     )?;
                     Ok((
                         key,
@@ -642,6 +1173,47 @@ fn tofu_repo_to_tag(
     tofu_repo_to_tag_(toml_name, updates, input, default_url, tag_regex).context(error_msg)
 }
 
+/// Path and tarball inputs have no branches or tags, only a rev / hash to pin.
+fn tofu_unversioned(input: &ParsedVCS) -> Result<(bool, TofuVCS)> {
+    Ok(match input {
+        ParsedVCS::Path {
+            path,
+            rev: Some(rev),
+        } => (
+            false,
+            TofuVCS::Path {
+                path: path.clone(),
+                rev: rev.clone(),
+            },
+        ),
+        ParsedVCS::Path { path, rev: None } => (
+            true,
+            TofuVCS::Path {
+                path: path.clone(),
+                rev: input.newest_revision("")?,
+            },
+        ),
+        ParsedVCS::Tarball {
+            url,
+            hash: Some(hash),
+        } => (
+            false,
+            TofuVCS::Tarball {
+                url: url.clone(),
+                hash: hash.clone(),
+            },
+        ),
+        ParsedVCS::Tarball { url, hash: None } => (
+            true,
+            TofuVCS::Tarball {
+                url: url.clone(),
+                hash: input.newest_revision("")?,
+            },
+        ),
+        _ => unreachable!("tofu_unversioned called on a versioned input: {input:?}"),
+    })
+}
+
 #[allow(clippy::too_many_lines)]
 fn tofu_repo_to_tag_(
     toml_name: &[&str],
@@ -651,6 +1223,12 @@ fn tofu_repo_to_tag_(
     tag_regex: &str,
 ) -> Result<vcs::TofuVCS> {
     let input = input.unwrap_or_else(|| default_url.try_into().expect("invalid default url"));
+    // a ?tag= in the url beats the default regex
+    let (input, url_tag_regex) = match input {
+        ParsedVCS::Tagged { vcs, tag_regex } => (*vcs, Some(tag_regex)),
+        other => (other, None),
+    };
+    let tag_regex = url_tag_regex.as_deref().unwrap_or(tag_regex);
     //debug!("tofu_repo_to_tag: {toml_name:?} from {input:?} with /{tag_regex}/");
     let (changed, out) = match &input {
         vcs::ParsedVCS::Git {
@@ -684,8 +1262,9 @@ fn tofu_repo_to_tag_(
             rev: None,
         } => {
             //branch is irrelevant, rev is now missing.
+            //nix wants git revs to be commit hashes, not tags
             let branch = input.discover_main_branch()?;
-            let rev = input.newest_tag(tag_regex)?;
+            let rev = input.newest_tag_and_rev(tag_regex)?.1;
             (
                 true,
                 vcs::TofuVCS::Git {
@@ -766,37 +1345,171 @@ fn tofu_repo_to_tag_(
                 rev: rev.to_string(),
             },
         ),
-        ParsedVCS::Mercurial {
-            url,
-            rev: Some(rev),
-        } => (
-            false,
-            TofuVCS::Mercurial {
-                url: url.to_string(),
-                rev: rev.to_string(),
-            },
-        ),
-        ParsedVCS::Mercurial { url, rev: None } => {
+        ParsedVCS::Mercurial { url, branch, rev } => {
+            // no ref= is mercurial's 'default' branch, that's not a change
+            let changed = rev.is_none();
+            let (branch, rev) = match (branch, rev) {
+                (branch, Some(rev)) => (
+                    branch.clone().unwrap_or_else(|| "default".to_string()),
+                    rev.clone(),
+                ),
+                // branch could either be a branch/bookmark. Or a tag. We have to discover it.
+                // nix wants hg revs to be hashes, not tags
+                (Some(branch), None) => match input.branch_or_tag(branch)? {
+                    BranchOrTag::Branch => (branch.clone(), input.newest_tag_and_rev(tag_regex)?.1),
+                    BranchOrTag::Tag => (
+                        input.discover_main_branch()?,
+                        input.newest_revision(branch)?,
+                    ),
+                },
+                (None, None) => (
+                    input.discover_main_branch()?,
+                    input.newest_tag_and_rev(tag_regex)?.1,
+                ),
+            };
             (
-                true,
+                changed,
                 TofuVCS::Mercurial {
-                    url: url.to_string(),
-                    rev: input.newest_revision("")?, //todo: we're ignoring mercurial
-                                                     //'branches/bookmarks' for  now
+                    url: url.clone(),
+                    branch,
+                    rev,
+                },
+            )
+        }
+        ParsedVCS::Path { .. } | ParsedVCS::Tarball { .. } => tofu_unversioned(&input)?,
+        ParsedVCS::Forge {
+            forge,
+            host,
+            owner,
+            repo,
+            branch,
+            rev,
+        } => {
+            let changed = branch.is_none() || rev.is_none();
+            let (branch, rev) = match (branch, rev) {
+                (Some(branch), Some(rev)) => (branch.clone(), rev.clone()),
+                // branch could either be a branch. Or a tag. We have to discover it.
+                (Some(branch), None) => match input.branch_or_tag(branch)? {
+                    BranchOrTag::Branch => (branch.clone(), input.newest_tag(tag_regex)?),
+                    BranchOrTag::Tag => (input.discover_main_branch()?, branch.clone()),
+                },
+                (None, Some(rev)) => (input.discover_main_branch()?, rev.clone()),
+                (None, None) => (input.discover_main_branch()?, input.newest_tag(tag_regex)?),
+            };
+            (
+                changed,
+                TofuVCS::Forge {
+                    forge: *forge,
+                    host: host.clone(),
+                    owner: owner.clone(),
+                    repo: repo.clone(),
+                    branch,
+                    rev,
                 },
             )
         }
+        ParsedVCS::Tagged { .. } => unreachable!("tag regexes are split off above"),
     };
     if changed {
         debug!("changed to {out:?}");
         updates.push((
             toml_name.iter().map(ToString::to_string).collect(),
-            value(out.to_string()),
+            value(vcs::with_tag_regex(
+                out.to_string(),
+                url_tag_regex.as_deref(),
+            )),
         ));
     }
     Ok(out)
 }
 
+/// `tag_regex = "..."` next to an url: follow the newest matching tag instead of the newest commit
+fn tofu_repo_to_tag_or_newest(
+    toml_name: &[&str],
+    updates: &mut TomlUpdates,
+    input: vcs::ParsedVCS,
+    tag_regex: Option<&str>,
+) -> Result<vcs::TofuVCS> {
+    match tag_regex {
+        Some(tag_regex) => {
+            if let ParsedVCS::Path { .. } | ParsedVCS::Tarball { .. } = input {
+                bail!("tag_regex is not supported for path: and tarball+ urls, they have no tags");
+            }
+            let url_tag_regex = url_tag_regex(&input);
+            let updates_before = updates.len();
+            let out = tofu_repo_to_tag(toml_name, updates, Some(input), "", tag_regex)?;
+            if updates.len() > updates_before {
+                use_git_for_unstable_github_tarballs(
+                    toml_name,
+                    updates,
+                    out,
+                    url_tag_regex.as_deref(),
+                )
+            } else {
+                Ok(out)
+            }
+        }
+        None => tofu_repo_to_newest(toml_name, updates, Some(input), ""),
+    }
+}
+
+fn url_tag_regex(input: &vcs::ParsedVCS) -> Option<String> {
+    match input {
+        ParsedVCS::Tagged { tag_regex, .. } => Some(tag_regex.clone()),
+        _ => None,
+    }
+}
+
+/// Workaround for github repos that break github's tarball consistency
+/// (.gitattributes export-subst) - fetch them with git instead.
+/// `url_tag_regex` is the `?tag=` of the url, it stays on the rewritten one.
+fn use_git_for_unstable_github_tarballs(
+    toml_name: &[&str],
+    updates: &mut TomlUpdates,
+    newest: vcs::TofuVCS,
+    url_tag_regex: Option<&str>,
+) -> Result<vcs::TofuVCS> {
+    // todo: this needs to be done once, but even if the user inputs everything, right?
+    let TofuVCS::GitHub {
+        owner,
+        repo,
+        branch,
+        rev,
+    } = &newest
+    else {
+        return Ok(newest);
+    };
+    let PrefetchHashResult::HaveToUseFetchGit = prefetch_github_hash(owner, repo, rev)? else {
+        return Ok(newest);
+    };
+    warn!("The github repo {owner}/{repo}/?rev={rev} is using .gitattributes and export-subst, which leads to the github tarball used by fetchFromGithub changing hashes over time.\nYour anysnake2.toml has been adjusted to use git directly instead, which is immune to that.");
+    // github urls may point to a tag, nix wants git revs to be commit hashes
+    let commit_regex = regex::Regex::new("^[a-f0-9]{40}$").unwrap();
+    let rev = if commit_regex.is_match(rev) {
+        rev.clone()
+    } else {
+        ParsedVCS::GitHub {
+            owner: owner.clone(),
+            repo: repo.clone(),
+            branch: None,
+            rev: None,
+        }
+        .newest_tag_and_rev(&format!("^{}$", regex::escape(rev)))?
+        .1
+    };
+    let (url, _, _) = newest.get_url_rev_branch();
+    let newest = TofuVCS::Git {
+        url,
+        branch: branch.clone(),
+        rev,
+    };
+    updates.push((
+        toml_name.iter().map(ToString::to_string).collect(),
+        value(vcs::with_tag_regex(newest.to_string(), url_tag_regex)),
+    ));
+    Ok(newest)
+}
+
 fn tofu_repo_to_newest(
     toml_name: &[&str],
     updates: &mut TomlUpdates,
@@ -805,36 +1518,17 @@ fn tofu_repo_to_newest(
 ) -> Result<vcs::TofuVCS> {
     let input = input.unwrap_or_else(|| default_url.try_into().expect("invalid default url"));
     let error_msg = format!("Trust-on-first-use-failed on {input:?}. Default url: {default_url}");
-    let (changed, mut newest) =
-        tofu_repo_to_newest_(toml_name, updates, &input).context(error_msg)?;
-
-    // workaround for repos that break githubs tar file consistency >
-    // todo: this needs to be done once, but even if the user inputs everything, right?
+    let (changed, newest) = tofu_repo_to_newest_(toml_name, updates, &input).context(error_msg)?;
     if changed {
-        if let TofuVCS::GitHub {
-            owner,
-            repo,
-            branch: _,
-            rev,
-        } = &newest
-        {
-            if let PrefetchHashResult::HaveToUseFetchGit = prefetch_github_hash(owner, repo, rev)? {
-                let (url, rev, branch) = newest.get_url_rev_branch();
-                warn!("The github repo {owner}/{repo}/?rev={rev} is using .gitattributes and export-subst, which leads to the github tarball used by fetchFromGithub changing hashes over time.\nYour anysnake2.toml has been adjusted to use git directly instead, which is immune to that.");
-
-                newest = TofuVCS::Git {
-                    url,
-                    branch: branch.to_string(),
-                    rev: rev.to_string(),
-                };
-                updates.push((
-                    toml_name.iter().map(ToString::to_string).collect(),
-                    value(newest.to_string()),
-                ));
-            }
-        }
+        use_git_for_unstable_github_tarballs(
+            toml_name,
+            updates,
+            newest,
+            url_tag_regex(&input).as_deref(),
+        )
+    } else {
+        Ok(newest)
     }
-    Ok(newest)
 }
 
 #[allow(clippy::too_many_lines)]
@@ -843,6 +1537,12 @@ fn tofu_repo_to_newest_(
     updates: &mut TomlUpdates,
     input: &vcs::ParsedVCS,
 ) -> Result<(bool, vcs::TofuVCS)> {
+    if let ParsedVCS::Tagged { .. } = input {
+        // follow the newest matching tag instead of a branch
+        let updates_before = updates.len();
+        let out = tofu_repo_to_tag_(toml_name, updates, Some(input.clone()), "", "")?;
+        return Ok((updates.len() > updates_before, out));
+    }
     let (changed, out) = match &input {
         vcs::ParsedVCS::Git {
             url,
@@ -936,26 +1636,64 @@ fn tofu_repo_to_newest_(
                 rev: rev.to_string(),
             },
         ),
-        ParsedVCS::Mercurial {
-            url,
-            rev: Some(rev),
-        } => (
-            false,
-            TofuVCS::Mercurial {
-                url: url.to_string(),
-                rev: rev.to_string(),
-            },
-        ),
-        ParsedVCS::Mercurial { url, rev: None } => {
+        ParsedVCS::Mercurial { url, branch, rev } => {
+            // no ref= is mercurial's 'default' branch, that's not a change
+            let branch = branch
+                .clone()
+                .map_or_else(|| input.discover_main_branch(), Ok)?;
+            match rev {
+                Some(rev) => (
+                    false,
+                    TofuVCS::Mercurial {
+                        url: url.clone(),
+                        branch,
+                        rev: rev.clone(),
+                    },
+                ),
+                None => (
+                    true,
+                    TofuVCS::Mercurial {
+                        url: url.clone(),
+                        rev: input.newest_revision(&branch)?,
+                        branch,
+                    },
+                ),
+            }
+        }
+        ParsedVCS::Path { .. } | ParsedVCS::Tarball { .. } => tofu_unversioned(input)?,
+        ParsedVCS::Forge {
+            forge,
+            host,
+            owner,
+            repo,
+            branch,
+            rev,
+        } => {
+            let changed = branch.is_none() || rev.is_none();
+            let (branch, rev) = match (branch, rev) {
+                (Some(branch), Some(rev)) => (branch.clone(), rev.clone()),
+                (branch, None) => {
+                    let branch = branch
+                        .clone()
+                        .map_or_else(|| input.discover_main_branch(), Ok)?;
+                    let rev = input.newest_revision(&branch)?;
+                    (branch, rev)
+                }
+                (None, Some(rev)) => (input.discover_main_branch()?, rev.clone()),
+            };
             (
-                true,
-                TofuVCS::Mercurial {
-                    url: url.to_string(),
-                    rev: input.newest_revision("")?, //todo: we're ignoring mercurial
-                                                     //'branches/bookmarks' for  now
+                changed,
+                TofuVCS::Forge {
+                    forge: *forge,
+                    host: host.clone(),
+                    owner: owner.clone(),
+                    repo: repo.clone(),
+                    branch,
+                    rev,
                 },
             )
         }
+        ParsedVCS::Tagged { .. } => unreachable!("tag regexes are split off above"),
     };
     if changed {
         //table["url"] = value(out.to_string());
@@ -988,10 +1726,11 @@ pub fn tofu_anysnake2_itself(
 pub fn apply_trust_on_first_use(
     //todo: Where ist the flake stuff?
     config: config::ConfigToml,
+    flake_dir: &Path,
 ) -> Result<TofuConfigToml> {
     let config_file = config.anysnake2_toml_path.as_ref().unwrap().clone();
     let mut updates: TomlUpdates = Vec::new();
-    let tofued = config.tofu(&mut updates)?;
+    let tofued = config.tofu(&mut updates, flake_dir)?;
     change_toml_file(&config_file, updates)?;
     Ok(tofued)
 }
@@ -1086,30 +1825,72 @@ impl Tofu<Option<config::TofuPython>> for Option<config::Python> {
     fn tofu(self, updates: &mut TomlUpdates) -> Result<Option<config::TofuPython>> {
         match self {
             Some(inner_self) => {
-                let tofu_packages: Result<HashMap<_, _>> = inner_self
-                    .packages
-                    .into_iter()
-                    .map(|(key, value)| {
-                        let new = tofu_python_package_definition(&key, &value, updates)
-                            .with_context(|| format!("Tofu python package failed: {key}"))?;
-                        Ok((key, new))
-                    })
-                    .collect();
+                let tofu_packages =
+                    tofu_python_packages(inner_self.packages, &["python", "packages"], updates)?;
 
-                let date = inner_self.ecosystem_date.unwrap_or_else(|| {
-                    //today in yyyy-mm-dd
-                    let date = jiff::Zoned::now().date().to_string();
-                    //chrono::Utc::now().format("%Y-%m-%d").to_string();
-                    let toml_path = vec!["python".to_string(), "ecosystem_date".to_string()];
-                    updates.push((toml_path, value(date.clone())));
-                    date
-                });
+                let date = tofu_ecosystem_date(
+                    inner_self.ecosystem_date,
+                    &["python", "ecosystem_date"],
+                    updates,
+                );
+
+                let mut envs = BTreeMap::new();
+                let valid_env_name = regex::Regex::new("^[a-zA-Z][a-zA-Z0-9_]*$").unwrap();
+                for (env_name, env) in inner_self.envs.unwrap_or_default() {
+                    if !valid_env_name.is_match(&env_name) {
+                        bail!("Invalid python env name '{env_name}'. Must start with a letter and contain only letters, digits and underscores.");
+                    }
+                    let packages = tofu_python_packages(
+                        env.packages,
+                        &["python", "envs", &env_name, "packages"],
+                        updates,
+                    )
+                    .with_context(|| format!("Tofu python env failed: {env_name}"))?;
+                    let ecosystem_date = tofu_ecosystem_date(
+                        env.ecosystem_date,
+                        &["python", "envs", &env_name, "ecosystem_date"],
+                        updates,
+                    );
+                    envs.insert(
+                        env_name,
+                        config::TofuPythonEnv {
+                            version: env.version,
+                            ecosystem_date,
+                            packages,
+                            uv_lock_env: env.uv_lock_env,
+                            default: env.default,
+                        },
+                    );
+                }
+                let default_envs: Vec<&str> = envs
+                    .iter()
+                    .filter(|(_, env)| env.default)
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                if default_envs.len() > 1 {
+                    bail!(
+                        "Only one python env may set default = true, found: {}",
+                        default_envs.join(", ")
+                    );
+                }
+                if let Some(default_env) = default_envs.first() {
+                    if envs.contains_key("python") {
+                        bail!("[python.envs.python] is reserved for the [python] environment when another env is the default");
+                    }
+                    if tofu_packages
+                        .values()
+                        .any(|spec| spec.editable_path.is_some())
+                    {
+                        bail!("Editable python packages need [python] to be /bin/python, but [python.envs.{default_env}] sets default = true");
+                    }
+                }
 
                 Ok(Some(config::TofuPython {
                     version: inner_self.version,
                     ecosystem_date: date,
-                    packages: tofu_packages?,
+                    packages: tofu_packages,
                     uv_lock_env: inner_self.uv_lock_env,
+                    envs,
                 }))
             }
             None => Ok(None),
@@ -1117,28 +1898,99 @@ impl Tofu<Option<config::TofuPython>> for Option<config::Python> {
     }
 }
 
+fn tofu_ecosystem_date(
+    date: Option<String>,
+    toml_path: &[&str],
+    updates: &mut TomlUpdates,
+) -> String {
+    date.unwrap_or_else(|| {
+        //today in yyyy-mm-dd
+        let date = jiff::Zoned::now().date().to_string();
+        //chrono::Utc::now().format("%Y-%m-%d").to_string();
+        let toml_path = toml_path.iter().map(ToString::to_string).collect();
+        updates.push((toml_path, value(date.clone())));
+        date
+    })
+}
+
+fn tofu_python_packages(
+    packages: HashMap<SafePythonName, config::PythonPackageDefinition>,
+    toml_prefix: &[&str],
+    updates: &mut TomlUpdates,
+) -> Result<HashMap<SafePythonName, config::TofuPythonPackageDefinition>> {
+    // PEP 508 - anything else would only fail much later, inside uv or nix
+    let valid_name =
+        regex::Regex::new("^([A-Za-z0-9]|[A-Za-z0-9][A-Za-z0-9._-]*[A-Za-z0-9])$").unwrap();
+    packages
+        .into_iter()
+        .map(|(key, value)| {
+            if !valid_name.is_match(&key.to_string()) {
+                bail!("Invalid python package name '{key}'. Must consist of letters, digits, '.', '-' and '_', and start and end with a letter or digit.");
+            }
+            let new = tofu_python_package_definition(&key, &value, toml_prefix, updates)
+                .with_context(|| format!("Tofu python package failed: {key}"))?;
+            Ok((key, new))
+        })
+        .collect()
+}
+
+/// http(s) urls that unpack to a source tree. Wheels can't be fetched as tarballs.
+fn is_python_sdist_url(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    (url.starts_with("https://") || url.starts_with("http://"))
+        && !path.to_ascii_lowercase().ends_with(".whl")
+}
+
 #[allow(clippy::enum_glob_use)]
 fn tofu_python_package_definition(
     name: &SafePythonName,
     ppd: &config::PythonPackageDefinition,
+    toml_prefix: &[&str],
     updates: &mut TomlUpdates,
 ) -> Result<config::TofuPythonPackageDefinition> {
     use config::TofuPythonPackageSource::*;
+    let name_str = name.to_string();
+    let toml_path: Vec<&str> = toml_prefix
+        .iter()
+        .copied()
+        .chain(std::iter::once(name_str.as_str()))
+        .collect();
     Ok(config::TofuPythonPackageDefinition {
         editable_path: ppd.editable_path.clone(),
         override_attrs: ppd.override_attrs.clone(),
         anysnake_override_attrs: ppd.anysnake_override_attrs.clone(),
         patch_before_lock: ppd.patch_before_lock.clone(),
         build_systems: ppd.build_systems.clone(),
+        ecosystem_date: ppd.ecosystem_date.clone(),
+        prefer: ppd.prefer,
+        patches: ppd.patches.clone(),
         source: match &ppd.source {
             config::PythonPackageSource::VersionConstraint(x) => VersionConstraint(x.to_string()),
-            config::PythonPackageSource::Url(x) => Url(x.to_string()),
-            config::PythonPackageSource::Vcs(parsed_vcs) => Vcs(tofu_repo_to_newest(
-                &["python", "packages", &name.to_string(), "url"],
-                updates,
-                Some(parsed_vcs.clone()),
-                "",
-            )?),
+            config::PythonPackageSource::Url(x) if is_python_sdist_url(x) => {
+                // pin it by hash like a tarball+ url - rewritten to tarball+...?narHash= on first use
+                let toml_name = [toml_path.as_slice(), &["url"]].concat();
+                Vcs(tofu_repo_to_tag_or_newest(
+                    &toml_name,
+                    updates,
+                    ParsedVCS::try_from(format!("tarball+{x}").as_str())?,
+                    ppd.tag_regex.as_deref(),
+                )?)
+            }
+            config::PythonPackageSource::Url(x) => {
+                debug!(
+                    "Python package {name}: {x} is not an http(s) sdist, left to uv.lock to pin"
+                );
+                Url(x.clone())
+            }
+            config::PythonPackageSource::Vcs(parsed_vcs) => {
+                let toml_name = [toml_path.as_slice(), &["url"]].concat();
+                Vcs(tofu_repo_to_tag_or_newest(
+                    &toml_name,
+                    updates,
+                    parsed_vcs.clone(),
+                    ppd.tag_regex.as_deref(),
+                )?)
+            }
             config::PythonPackageSource::PyPi { version } => {
                 let pypi_version = match version.as_ref().map(String::as_str) {
                     None | Some("") => get_newest_pypi_version(name)
@@ -1152,11 +2004,7 @@ fn tofu_python_package_definition(
 
                 if push {
                     updates.push((
-                        vec![
-                            "python".to_string(),
-                            "packages".to_string(),
-                            name.to_string(),
-                        ],
+                        toml_path.iter().map(ToString::to_string).collect(),
                         value(out),
                     ));
                 }
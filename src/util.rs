@@ -257,10 +257,16 @@ pub fn get_proxy_req() -> Result<ureq::Agent> {
     Ok(agent.build().into())
 }
 
+/// A source distribution on PyPI
+pub struct PypiSdist {
+    pub url: String,
+    pub sha256: String,
+}
+
 pub fn get_pypi_package_source_url(
     package_name: &str,
     pypi_version: Option<&str>,
-) -> Result<String> {
+) -> Result<PypiSdist> {
     let json = get_proxy_req()?
         .get(&format!("https://pypi.org/pypi/{package_name}/json"))
         .call()?
@@ -289,7 +295,13 @@ pub fn get_pypi_package_source_url(
     };
     for file in files {
         if file["packagetype"] == "sdist" {
-            return Ok(file["url"].as_str().context("no url in json")?.to_string());
+            return Ok(PypiSdist {
+                url: file["url"].as_str().context("no url in json")?.to_string(),
+                sha256: file["digests"]["sha256"]
+                    .as_str()
+                    .context("no sha256 digest in json")?
+                    .to_string(),
+            });
         }
     }
     bail!("Could not find a sdist release");
@@ -0,0 +1,518 @@
+use anyhow::{bail, Context, Result};
+use clap::ArgMatches;
+#[allow(unused_imports)]
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use version_compare::Version;
+
+use crate::config;
+use crate::flake_writer::ancient_poetry_exclusions;
+use crate::pypi::{
+    fetch_pypi_json, newest_version, normalize_name, parse_requirement, pypi_release_dates,
+};
+
+/// the name of the project anysnake2 generates for ancient-poetry/uv
+const ROOT_PROJECT: &str = "anysnake2-to-ancient-poetry-uv";
+
+#[derive(Debug)]
+struct LockedPackage {
+    version: String,
+    source: String,
+    dependencies: Vec<String>,
+    // only filled for our root project
+    requires_dist: HashMap<String, String>,
+}
+
+/// the python environment (and it's uv.lock) 'why python' answers for
+struct WhyPythonEnv<'a> {
+    /// "python" or "python.envs.<name>"
+    section: String,
+    uv_lock_path: PathBuf,
+    packages: &'a HashMap<config::SafePythonName, config::TofuPythonPackageDefinition>,
+    ecosystem_date: &'a str,
+}
+
+/// `--env <name>`, or the default env (which is [python] unless an env sets default = true).
+/// `--env python` refers to [python] itself, unless there's a [python.envs.python]
+fn select_python_env<'a>(
+    flake_dir: &Path,
+    python: &'a config::TofuPython,
+    env_name: Option<&str>,
+) -> Result<WhyPythonEnv<'a>> {
+    let env_name = env_name.or_else(|| python.default_env());
+    match env_name {
+        Some(env_name) if python.envs.contains_key(env_name) => {
+            let env = &python.envs[env_name];
+            Ok(WhyPythonEnv {
+                section: format!("python.envs.{env_name}"),
+                uv_lock_path: flake_dir.join("uv").join(env_name).join("uv.lock"),
+                packages: &env.packages,
+                ecosystem_date: &env.ecosystem_date,
+            })
+        }
+        None | Some("python") => Ok(WhyPythonEnv {
+            section: "python".to_string(),
+            uv_lock_path: flake_dir.join("uv").join("uv.lock"),
+            packages: &python.packages,
+            ecosystem_date: &python.ecosystem_date,
+        }),
+        Some(env_name) => bail!(
+            "No [python.envs.{env_name}] in anysnake2.toml. Available: python, {}",
+            python.envs.keys().cloned().collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+pub fn handle_why_command(
+    flake_dir: &Path,
+    parsed_config: &config::TofuConfigToml,
+    matches: &ArgMatches,
+) -> Result<()> {
+    match matches.subcommand() {
+        Some(("python", sc)) => {
+            let package = sc
+                .get_one::<String>("package")
+                .context("No package passed to 'why python'")?;
+            let python = parsed_config
+                .python
+                .as_ref()
+                .context("No [python] section in anysnake2.toml")?;
+            let env = select_python_env(
+                flake_dir,
+                python,
+                sc.get_one::<String>("env").map(String::as_str),
+            )?;
+            why_python(&env, package)
+        }
+        _ => {
+            bail!("Please pass an ecosystem, as in 'anysnake2 why python <package>'");
+        }
+    }
+}
+
+fn parse_uv_lock(uv_lock_path: &Path) -> Result<HashMap<String, LockedPackage>> {
+    let raw = ex::fs::read_to_string(uv_lock_path).with_context(|| {
+        format!(
+            "Could not read {}. Run 'anysnake2 build flake' first to generate it",
+            uv_lock_path.display()
+        )
+    })?;
+    let parsed: toml::Table = toml::from_str(&raw).context("Failed to parse uv.lock")?;
+    let mut res = HashMap::new();
+    for pkg_info in parsed
+        .get("package")
+        .and_then(toml::Value::as_array)
+        .context("No package sections in uv.lock?")?
+    {
+        let name = pkg_info["name"]
+            .as_str()
+            .context("No name in package section")?;
+        let version = pkg_info
+            .get("version")
+            .and_then(toml::Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let source = pkg_info.get("source").map_or_else(String::new, |source| {
+            source.as_table().map_or_else(
+                || source.to_string(),
+                |tbl| {
+                    tbl.iter()
+                        .map(|(k, v)| format!("{k}={}", v.as_str().unwrap_or_default()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                },
+            )
+        });
+        let mut dependencies = Vec::new();
+        let dep_names = |deps: &toml::Value| -> Vec<String> {
+            deps.as_array()
+                .map(|deps| {
+                    deps.iter()
+                        .filter_map(|dep| dep.get("name").and_then(toml::Value::as_str))
+                        .map(normalize_name)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        if let Some(deps) = pkg_info.get("dependencies") {
+            dependencies.extend(dep_names(deps));
+        }
+        if let Some(optional) = pkg_info
+            .get("optional-dependencies")
+            .and_then(toml::Value::as_table)
+        {
+            for deps in optional.values() {
+                dependencies.extend(dep_names(deps));
+            }
+        }
+        dependencies.sort();
+        dependencies.dedup();
+
+        let mut requires_dist = HashMap::new();
+        if let Some(requirements) = pkg_info
+            .get("metadata")
+            .and_then(|x| x.get("requires-dist"))
+            .and_then(toml::Value::as_array)
+        {
+            for req in requirements {
+                if let Some(req_name) = req.get("name").and_then(toml::Value::as_str) {
+                    let specifier = req
+                        .get("specifier")
+                        .and_then(toml::Value::as_str)
+                        .unwrap_or("");
+                    requires_dist.insert(normalize_name(req_name), specifier.to_string());
+                }
+            }
+        }
+        res.insert(
+            normalize_name(name),
+            LockedPackage {
+                version,
+                source,
+                dependencies,
+                requires_dist,
+            },
+        );
+    }
+    Ok(res)
+}
+
+/// the shortest dependency chain from `start` to `target`, if any
+fn shortest_chain(
+    lock: &HashMap<String, LockedPackage>,
+    start: &str,
+    target: &str,
+) -> Option<Vec<String>> {
+    let mut parents: HashMap<&str, &str> = HashMap::new();
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut queue = VecDeque::new();
+    seen.insert(start);
+    queue.push_back(start);
+    while let Some(current) = queue.pop_front() {
+        if current == target {
+            let mut chain = vec![current.to_string()];
+            let mut cursor = current;
+            while let Some(parent) = parents.get(cursor) {
+                chain.push((*parent).to_string());
+                cursor = parent;
+            }
+            chain.reverse();
+            return Some(chain);
+        }
+        if let Some(pkg) = lock.get(current) {
+            for dep in &pkg.dependencies {
+                if seen.insert(dep.as_str()) {
+                    parents.insert(dep.as_str(), current);
+                    queue.push_back(dep.as_str());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// all dependency chains, one per top level package that (transitively) requires `target`
+fn dependency_chains(lock: &HashMap<String, LockedPackage>, target: &str) -> Vec<Vec<String>> {
+    let Some(root) = lock.get(ROOT_PROJECT) else {
+        return Vec::new();
+    };
+    let mut chains: Vec<Vec<String>> = root
+        .dependencies
+        .iter()
+        .filter_map(|top_level| shortest_chain(lock, top_level, target))
+        .collect();
+    chains.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+    chains
+}
+
+/// what constraint did `parent` (at it's locked version) place on `child`?
+fn constraint_from_pypi(parent: &str, parent_version: &str, child: &str) -> Result<String> {
+    let json = fetch_pypi_json(parent, Some(parent_version))?;
+    let requirements = json["info"]["requires_dist"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    let mut hits = Vec::new();
+    for req in requirements.iter().filter_map(serde_json::Value::as_str) {
+        let (name, specifier, marker) = parse_requirement(req);
+        if name == child {
+            let specifier = if specifier.is_empty() {
+                "any version".to_string()
+            } else {
+                specifier
+            };
+            hits.push(match marker {
+                Some(marker) => format!("{specifier} (if {marker})"),
+                None => specifier,
+            });
+        }
+    }
+    if hits.is_empty() {
+        Ok("unknown (not listed in PyPI metadata)".to_string())
+    } else {
+        Ok(hits.join(" | "))
+    }
+}
+
+fn explain_version_limit(env: &WhyPythonEnv, name: &str, locked: &LockedPackage) -> Result<String> {
+    if let Some(pinned) = ancient_poetry_exclusions(env.packages)
+        .iter()
+        .find(|(pkg, _)| normalize_name(pkg) == name)
+        .map(|(_, version)| version)
+    {
+        return Ok(format!(
+            "pinned to {pinned} via 'pypi:{pinned}' in anysnake2.toml. It was passed to ancient-poetry via --exclusion-list, so ecosystem_date = {} did not apply.",
+            env.ecosystem_date
+        ));
+    }
+    if !locked.source.starts_with("registry") {
+        return Ok(format!(
+            "not resolved from a package index (source: {}), ecosystem_date does not apply.",
+            locked.source
+        ));
+    }
+    let json = fetch_pypi_json(name, None)?;
+    let release_dates = pypi_release_dates(&json);
+    let package_ecosystem_date = env
+        .packages
+        .iter()
+        .find(|(pkg, _)| normalize_name(pkg.as_str()) == name)
+        .and_then(|(_, spec)| spec.ecosystem_date.as_ref());
+    let ecosystem_date = match package_ecosystem_date {
+        Some(date) => format!("{date} (set for this package)"),
+        None => env.ecosystem_date.to_string(),
+    };
+    let date_limit = package_ecosystem_date.map_or(env.ecosystem_date, String::as_str);
+    let available = release_dates
+        .iter()
+        .filter(|(_, date)| date.as_str() <= date_limit)
+        .map(|(version, _)| version);
+    let newest_available = newest_version(available);
+    let newest_overall = newest_version(release_dates.keys());
+    let locked_version = Version::from(&locked.version);
+    let is_newer = |other: &String| match (Version::from(other), &locked_version) {
+        (Some(other), Some(locked)) => other > *locked,
+        _ => false,
+    };
+
+    Ok(match (newest_available, newest_overall) {
+        (Some(available), _) if is_newer(available) => format!(
+            "not limited by ecosystem_date = {ecosystem_date} ({available} was already released on {}). It's held back by the dependency constraints above.",
+            release_dates[available]
+        ),
+        (_, Some(overall)) if is_newer(overall) => format!(
+            "limited by ecosystem_date = {ecosystem_date}. The newest release on PyPI is {overall} (released {}).",
+            release_dates[overall]
+        ),
+        _ => format!("{} is the newest release on PyPI.", locked.version),
+    })
+}
+
+fn why_python(env: &WhyPythonEnv, query: &str) -> Result<()> {
+    let uv_lock_path = &env.uv_lock_path;
+    let lock = parse_uv_lock(uv_lock_path)?;
+    let name = normalize_name(query);
+    let locked = lock
+        .get(&name)
+        .with_context(|| format!("Package {query} not found in {}", uv_lock_path.display()))?;
+    println!("{name} {}", locked.version);
+    let chains = dependency_chains(&lock, &name);
+    if chains.is_empty() {
+        println!("\tnot required by any of the [{}.packages]", env.section);
+    }
+
+    let mut constraint_cache: HashMap<(String, String), String> = HashMap::new();
+    for chain in &chains {
+        println!();
+        println!("\tvia {}", chain.join(" -> "));
+        let top_level = &chain[0];
+        let top_level_constraint = lock
+            .get(ROOT_PROJECT)
+            .and_then(|root| root.requires_dist.get(top_level))
+            .map_or("", String::as_str);
+        let top_level_constraint = if top_level_constraint.is_empty() {
+            "any version"
+        } else {
+            top_level_constraint
+        };
+        println!(
+            "\t\t[{}.packages] requires {top_level} {top_level_constraint} (locked {})",
+            env.section, lock[top_level].version
+        );
+        for pair in chain.windows(2) {
+            let (parent, child) = (&pair[0], &pair[1]);
+            let parent_version = &lock[parent].version;
+            let constraint = constraint_cache
+                .entry((parent.clone(), child.clone()))
+                .or_insert_with(|| {
+                    constraint_from_pypi(parent, parent_version, child).unwrap_or_else(|e| {
+                        warn!("Could not query PyPI for {parent} {parent_version}: {e}");
+                        "unknown".to_string()
+                    })
+                });
+            println!(
+                "\t\t{parent} {parent_version} requires {child} {constraint} (locked {})",
+                lock[child].version
+            );
+        }
+    }
+    println!();
+    match explain_version_limit(env, &name, locked) {
+        Ok(explanation) => println!("\t{name} {} is {explanation}", locked.version),
+        Err(e) => {
+            warn!("Could not determine whether {name} was limited by the ecosystem date: {e}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dependency_chains() {
+        let raw = r#"
+version = 1
+requires-python = "==3.12.*"
+
+[[package]]
+name = "anysnake2-to-ancient-poetry-uv"
+version = "0.1.0"
+source = { virtual = "." }
+dependencies = [
+    { name = "pandas" },
+    { name = "scanpy" },
+]
+
+[package.metadata]
+requires-dist = [
+    { name = "pandas", specifier = "==1.5.1" },
+    { name = "scanpy", specifier = ">=0" },
+]
+
+[[package]]
+name = "numpy"
+version = "1.26.4"
+source = { registry = "https://pypi.org/simple" }
+
+[[package]]
+name = "pandas"
+version = "1.5.1"
+source = { registry = "https://pypi.org/simple" }
+dependencies = [
+    { name = "numpy" },
+    { name = "python-dateutil" },
+]
+
+[[package]]
+name = "python-dateutil"
+version = "2.9.0"
+source = { registry = "https://pypi.org/simple" }
+
+[[package]]
+name = "scanpy"
+version = "1.10.0"
+source = { registry = "https://pypi.org/simple" }
+dependencies = [
+    { name = "pandas" },
+]
+"#;
+        let td = tempfile::tempdir().unwrap();
+        let path = td.path().join("uv.lock");
+        ex::fs::write(&path, raw).unwrap();
+        let lock = parse_uv_lock(&path).unwrap();
+        assert_eq!(lock["python-dateutil"].version, "2.9.0");
+        assert_eq!(
+            lock[ROOT_PROJECT].requires_dist.get("pandas").unwrap(),
+            "==1.5.1"
+        );
+        let chains = dependency_chains(&lock, "numpy");
+        assert_eq!(
+            chains,
+            vec![
+                vec!["pandas".to_string(), "numpy".to_string()],
+                vec![
+                    "scanpy".to_string(),
+                    "pandas".to_string(),
+                    "numpy".to_string()
+                ],
+            ]
+        );
+        assert!(dependency_chains(&lock, "scanpy") == vec![vec!["scanpy".to_string()]]);
+    }
+
+    #[test]
+    fn test_select_python_env() {
+        let env = |ecosystem_date: &str, default: bool| config::TofuPythonEnv {
+            version: "3.11".to_string(),
+            ecosystem_date: ecosystem_date.to_string(),
+            packages: HashMap::new(),
+            uv_lock_env: None,
+            default,
+        };
+        let mut python = config::TofuPython {
+            version: "3.12".to_string(),
+            ecosystem_date: "2024-01-01".to_string(),
+            packages: HashMap::new(),
+            uv_lock_env: None,
+            envs: [
+                ("legacy".to_string(), env("2022-01-01", true)),
+                ("other".to_string(), env("2023-01-01", false)),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let td = tempfile::tempdir().unwrap();
+        let env_lock = td.path().join("uv").join("legacy").join("uv.lock");
+        ex::fs::create_dir_all(env_lock.parent().unwrap()).unwrap();
+        ex::fs::write(
+            &env_lock,
+            r#"
+version = 1
+
+[[package]]
+name = "anysnake2-to-ancient-poetry-uv"
+version = "0.1.0"
+source = { virtual = "." }
+dependencies = [{ name = "numpy" }]
+
+[[package]]
+name = "numpy"
+version = "1.22.4"
+source = { registry = "https://pypi.org/simple" }
+"#,
+        )
+        .unwrap();
+
+        // the default env's lock, not [python]'s
+        let selected = select_python_env(td.path(), &python, None).unwrap();
+        assert_eq!(selected.uv_lock_path, env_lock);
+        assert_eq!(selected.ecosystem_date, "2022-01-01");
+        assert_eq!(selected.section, "python.envs.legacy");
+        let lock = parse_uv_lock(&selected.uv_lock_path).unwrap();
+        assert_eq!(lock["numpy"].version, "1.22.4");
+        assert_eq!(
+            dependency_chains(&lock, "numpy"),
+            vec![vec!["numpy".to_string()]]
+        );
+
+        let selected = select_python_env(td.path(), &python, Some("other")).unwrap();
+        assert_eq!(
+            selected.uv_lock_path,
+            td.path().join("uv").join("other").join("uv.lock")
+        );
+        assert_eq!(selected.ecosystem_date, "2023-01-01");
+
+        let selected = select_python_env(td.path(), &python, Some("python")).unwrap();
+        assert_eq!(selected.uv_lock_path, td.path().join("uv").join("uv.lock"));
+        assert_eq!(selected.ecosystem_date, "2024-01-01");
+
+        assert!(select_python_env(td.path(), &python, Some("nope")).is_err());
+
+        python.envs.get_mut("legacy").unwrap().default = false;
+        let selected = select_python_env(td.path(), &python, None).unwrap();
+        assert_eq!(selected.section, "python");
+    }
+}